@@ -1,31 +1,88 @@
-use std::collections::HashMap;
-use std::fs::{create_dir, create_dir_all, metadata, OpenOptions, read_dir, remove_file};
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs::{canonicalize, create_dir, create_dir_all, metadata, File, Metadata, OpenOptions, read_dir, remove_file};
+use std::io::{self, ErrorKind, Write};
 use std::path::{Path, PathBuf};
-use crate::file_types::{FileType, TextFile};
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, error, info, warn};
+use crate::fuzzy::{fuzzy_match, substring_match};
+use crate::highlight::Highlights;
+use crate::history::TextHistory;
+use crate::lines::Layouts;
+use crate::index::SearchIndex;
+use crate::operations::OperationLog;
+use crate::sorting::SortMode;
+use crate::file_types::{FileInfo, FileType, InaccessibleEntry, TextFile};
+
+// Editor data kept inside the workspace, hidden from the tree
+pub const META_DIR: &str = ".editor";
+
+pub(crate) struct SaveFailure {
+    pub key: String,
+    pub error: io::Error
+}
 
 pub(crate) struct Database {
     pub data: HashMap<String, FileType>,
     file_path: String,
-    collapsed: Vec<String>
+    collapsed: Vec<String>,
+    follow_symlinks: bool,
+    pub sort_mode: SortMode,
+    pub folders_first: bool,
+    filter: String,
+    fuzzy_filter: bool,
+    pub selection: Vec<String>,
+    pub selection_anchor: Option<String>,
+    pub operations: OperationLog,
+    pub clipboard: Vec<String>,
+    pub clipboard_cut: bool,
+    pub index: SearchIndex,
+    pub history: HashMap<String, TextHistory>,
+    // Whether the undo history is written to disk to outlive the session
+    pub keep_history: bool,
+    pub highlights: Highlights,
+    pub layouts: Rc<Layouts>
 }
 
 impl Database {
-    pub fn new(file_path: &str) -> Self {
+    pub fn new(file_path: &str, follow_symlinks: bool) -> Self {
         if !metadata(&file_path).is_ok() {
-            create_dir(&file_path).expect("created cache failed");
-            println!("Directory created successfully!");
+            // If this fails, load reports it along with whatever else is wrong with the folder
+            match create_dir(&file_path) {
+                Ok(()) => info!("Created {}", file_path),
+                Err(e) => error!("Couldn't create {}: {}", file_path, e),
+            }
         } else {
-            println!("Directory already exists!");
+            debug!("{} already exists", file_path);
         }
 
         Database {
             data: HashMap::new(),
             file_path: file_path.to_string(),
-            collapsed: Vec::new()
+            collapsed: Vec::new(),
+            follow_symlinks,
+            sort_mode: SortMode::Name,
+            folders_first: true,
+            filter: String::new(),
+            fuzzy_filter: false,
+            selection: Vec::new(),
+            selection_anchor: None,
+            operations: OperationLog::default(),
+            clipboard: Vec::new(),
+            clipboard_cut: false,
+            index: SearchIndex::default(),
+            history: HashMap::new(),
+            keep_history: false,
+            highlights: Highlights::default(),
+            layouts: Rc::new(Layouts::default())
         }
     }
 
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
+    }
+
     pub fn toggle_collapse(&mut self, file: &str) {
         if self.collapsed.contains(&file.to_string()) {
             self.collapsed = self.collapsed.iter().cloned().filter(|s| { *s != file.to_string() }).collect::<Vec<_>>()
@@ -34,18 +91,33 @@ impl Database {
         }
     }
 
-    pub fn collapsed(&self, file: &str) -> bool {
-        let a = file.split("/").collect::<Vec<_>>();
-        let mut s = String::from("");
-        for m in a.clone() {
-            s.push_str(m);
-            for x in self.collapsed.clone() {
-                if x == s.clone() { return true; }
-            }
-            s.push('/')
+    pub fn set_filter(&mut self, filter: &str, fuzzy: bool) {
+        self.filter = filter.trim().to_string();
+        self.fuzzy_filter = fuzzy;
+    }
+
+    pub fn is_filtering(&self) -> bool {
+        !self.filter.is_empty()
+    }
+
+    pub fn matches_filter(&self, key: &str) -> bool {
+        if self.filter.is_empty() {
+            true
+        } else if self.fuzzy_filter {
+            fuzzy_match(&self.filter, key)
+        } else {
+            substring_match(&self.filter, key)
         }
+    }
+
+    // Keys shown in the tree. While a filter is active only matching keys are
+    // kept; their folders come along when the tree is built.
+    pub fn visible_keys(&self) -> Vec<String> {
+        self.data.keys().filter(|key| self.matches_filter(key)).cloned().collect()
+    }
 
-        return false;
+    pub fn is_collapsed(&self, folder: &str) -> bool {
+        self.collapsed.iter().any(|c| c == folder)
     }
 
     pub fn contains(&self, file: &str) -> bool {
@@ -56,133 +128,290 @@ impl Database {
         return self.data.get_mut(file_name);
     }
 
-    pub fn keys(&self) -> Vec<&String> {
-        return self.data.keys().collect()
-    }
-
     pub fn change_dirs(&mut self, p: String) {
+        if let Err(e) = self.save_index() {
+            warn!("Couldn't save the search index: {}", e);
+        }
+        if let Err(e) = self.save_history() {
+            warn!("Couldn't save the undo history: {}", e);
+        }
+        self.index = SearchIndex::default();
+        self.history.clear();
         self.file_path = p;
         self.data.clear();
+        self.selection.clear();
+        self.selection_anchor = None;
+        self.operations = OperationLog::default();
+        self.clipboard.clear();
     }
 
     pub fn current_dir(&self) -> String {
         return self.file_path.clone();
     }
 
+    pub fn meta_dir(&self) -> PathBuf {
+        Path::new(&self.file_path).join(META_DIR)
+    }
+
     pub fn load(&mut self) -> Result<(), io::Error> {
-        self.read_dir_recursive(&PathBuf::from(&self.file_path), String::new())?;
+        let root = PathBuf::from(&self.file_path);
+        let mut ancestors = HashSet::new();
+        if let Ok(canonical) = canonicalize(&root) {
+            ancestors.insert(canonical);
+        }
+        self.read_dir_recursive(&root, String::new(), &mut ancestors)?;
+        self.load_index();
+        self.load_history();
         Ok(())
     }
 
-    fn read_dir_recursive(&mut self, path: &Path, prefix: String) -> Result<(), io::Error> {
-        if let Ok(entries) = read_dir(path) {
-            for entry in entries {
-                let entry = entry?;
-                let path = entry.path();
-                let file_name = entry.file_name().into_string().unwrap();
-
-                if path.is_dir() {
-                    let new_prefix = if prefix.is_empty() {
-                        file_name.clone()
-                    } else {
-                        format!("{}/{}", prefix, file_name)
-                    };
-                    self.read_dir_recursive(&path, new_prefix)?;
-                } else {
-                 //   let mut content = String::new();
-               //     let mut file = File::open(&path)?;
-               //     let r = file.read_to_string(&mut content);
-                //    if r.is_err() { continue; }
-
-                    let key = if prefix.is_empty() {
-                        file_name.clone()
-                    } else {
-                        format!("{}/{}", prefix, file_name)
-                    };
-                    self.data.insert(key.clone(), FileType::Text(TextFile { path: path.as_os_str().to_str().unwrap().to_string(), content: None, dirty: false }));
+    // `ancestors` holds the canonical paths of the directories being walked
+    // down to this one, so a symlink pointing back up the tree is skipped
+    // instead of recursing forever. A folder reached twice some other way, e.g.
+    // through two links to it, is listed both times.
+    fn read_dir_recursive(&mut self, path: &Path, prefix: String, ancestors: &mut HashSet<PathBuf>) -> Result<(), io::Error> {
+        let entries = match read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read directory {:?}: {}", path, e);
+                if !prefix.is_empty() {
+                    self.insert_inaccessible(prefix, path.to_path_buf(), e.to_string());
                 }
+                return Ok(());
+            }
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping unreadable entry in {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if prefix.is_empty() && file_name == META_DIR {
+                continue;
+            }
+            let key = if prefix.is_empty() {
+                file_name.clone()
+            } else {
+                format!("{}/{}", prefix, file_name)
+            };
+            // Lossy names can collide, e.g. two names that only differ in invalid bytes
+            let key = if self.data.contains_key(&key) { self.get_unique_key(&key) } else { key };
+
+            let is_symlink = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+            let meta = match metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) if is_symlink && e.kind() == ErrorKind::NotFound => {
+                    info!("Skipping broken symlink {:?}", path);
+                    continue;
+                }
+                Err(e) => {
+                    self.insert_inaccessible(key, path, e.to_string());
+                    continue;
+                }
+            };
+
+            if meta.is_dir() {
+                if is_symlink && !self.follow_symlinks {
+                    continue;
+                }
+                let canonical = match canonicalize(&path) {
+                    Ok(canonical) => canonical,
+                    Err(e) => {
+                        self.insert_inaccessible(key, path, e.to_string());
+                        continue;
+                    }
+                };
+                if !ancestors.insert(canonical.clone()) {
+                    info!("Skipping symlink cycle at {:?}", path);
+                    continue;
+                }
+                let result = self.read_dir_recursive(&path, key, ancestors);
+                ancestors.remove(&canonical);
+                result?;
+            } else {
+                let read_only = match read_only(&path, &meta) {
+                    Ok(read_only) => read_only,
+                    Err(e) => {
+                        self.insert_inaccessible(key, path, e.to_string());
+                        continue;
+                    }
+                };
+                let info = FileInfo::from_metadata(&meta);
+                self.data.insert(key, FileType::Text(TextFile { path, content: None, dirty: false, read_only, info }));
             }
-        } else {
-            println!("Failed to read directory: {:?}", path);
         }
 
         Ok(())
     }
 
-    pub fn save_all(&self) -> io::Result<()> {
-        for (key, value) in &self.data {
-
-            if let FileType::Text(text_file) = value {
-                if !value.is_dirty() { continue; }
-                let file_path = format!("{}/{}", &self.file_path, key);
-                let path = Path::new(&file_path);
-
-                // Ensure the parent directory exists
-                if let Some(parent) = path.parent() {
-                    create_dir_all(parent).expect("Failed to create dir");
-                }
+    fn insert_inaccessible(&mut self, key: String, path: PathBuf, reason: String) {
+        warn!("Can't access {:?}: {}", path, reason);
+        self.data.insert(key, FileType::Inaccessible(InaccessibleEntry { path, reason }));
+    }
 
-                // Open the file with write, truncate, and create options
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .create(true)
-                    .open(&file_path).expect(format!("Failed to open file {}", key.clone()).as_str());
+    // The on-disk location of a key. Scanned entries keep their original OsString
+    // path, so names that aren't valid UTF-8 still round-trip.
+    pub fn disk_path(&self, key: &str) -> PathBuf {
+        match self.data.get(key) {
+            Some(FileType::Text(file)) => file.path.clone(),
+            Some(FileType::Image(file)) => file.path.clone(),
+            Some(FileType::Inaccessible(entry)) => entry.path.clone(),
+            None => Path::new(&self.file_path).join(key),
+        }
+    }
 
-                println!("Saved {}", key);
+    // Keeps going past files that can't be written so one read-only note
+    // doesn't stop everything else from being saved.
+    pub fn save_all(&mut self) -> Result<(), Vec<SaveFailure>> {
+        let mut failures = Vec::new();
+        for (key, value) in &mut self.data {
 
-                // Write the value to the file
+            if let FileType::Text(text_file) = value {
+                if !text_file.dirty { continue; }
                 if let Some(content) = &text_file.content {
-                    writeln!(&mut file, "{}", content.clone()).expect(format!("Failed to save {}", key.clone()).as_str());
+                    match write_contents(&text_file.path, content) {
+                        Ok(()) => {
+                            info!("Saved {}", key);
+                            text_file.dirty = false;
+                            text_file.info = FileInfo::read(&text_file.path);
+                            self.index.update(key, content.as_bytes(), text_file.info);
+                        }
+                        Err(error) => {
+                            error!("Failed to save {}: {}", key, error);
+                            failures.push(SaveFailure { key: key.clone(), error });
+                        }
+                    }
                 }
             }
         }
+        if failures.is_empty() { Ok(()) } else { Err(failures) }
+    }
+
+    pub fn save_as(&mut self, key: &str, target: &Path) -> io::Result<()> {
+        let content = self.get_contents(key)?;
+        write_contents(target, &content)?;
+        self.mark_saved(key);
         Ok(())
     }
 
-    pub fn delete_file(&self, name: &str) {
-        if remove_file(format!("{}/{}", &self.file_path, name)).is_ok() {
-            println!("Deleted file {}", name);
+    // Writes the contents to a temporary copy and asks the OS to move it over
+    // the original with elevated privileges (pkexec, osascript or UAC).
+    pub fn save_elevated(&mut self, key: &str) -> io::Result<()> {
+        let content = self.get_contents(key)?;
+        // The elevated process doesn't start in our working directory
+        let target = std::path::absolute(self.disk_path(key))?;
+        let (temp, mut file) = create_temp()?;
+        let written = file.write_all(content.as_bytes());
+        drop(file);
+        if let Err(e) = written {
+            let _ = remove_file(&temp);
+            return Err(e);
+        }
+
+        let status = elevated_copy_command(&temp, &target).status();
+        let _ = remove_file(&temp);
+        match status {
+            Ok(status) if status.success() => {
+                self.mark_saved(key);
+                Ok(())
+            }
+            Ok(status) => Err(io::Error::new(ErrorKind::PermissionDenied, format!("elevated copy exited with {}", status))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_contents(&mut self, key: &str) -> io::Result<String> {
+        match self.data.get_mut(key) {
+            Some(FileType::Text(text_file)) => Ok(text_file.get_contents()),
+            _ => Err(io::Error::new(ErrorKind::NotFound, format!("{} is not a text file", key))),
         }
     }
 
     pub fn rename(&mut self, old: &str, new: &str) {
         let contents = self.data.remove(old);
-        if contents.is_some() {
-            self.data.insert(new.to_owned(), contents.unwrap());
+        if let Some(mut contents) = contents {
+            if let FileType::Text(ref mut text_file) = contents {
+                text_file.path = Path::new(&self.file_path).join(new);
+            }
+            self.data.insert(new.to_owned(), contents);
+        }
+        self.index.rename(old, new);
+        if let Some(history) = self.history.remove(old) {
+            self.history.insert(new.to_owned(), history);
         }
     }
 
     pub fn remove(&mut self, str: &str) {
         self.data.remove(str);
+        self.index.remove(str);
+        self.history.remove(str);
     }
+    // Also steps around folders, and keeps the extension at the end ("a 1.md")
     pub fn get_unique_key(&self, key: &str) -> String {
+        let name_start = key.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (stem, extension) = match key[name_start..].rfind('.') {
+            Some(dot) if dot > 0 => key.split_at(name_start + dot),
+            _ => (key, ""),
+        };
         let mut new_key = key.to_string();
         let mut count = 1;
-        while self.data.contains_key(&new_key) {
-            new_key = format!("{} {}", key, count);
+        while self.data.contains_key(&new_key) || self.is_folder(&new_key) {
+            new_key = format!("{} {}{}", stem, count, extension);
             count += 1;
         }
         new_key
     }
 
-    pub fn save(&self, file: &str) {
-        if let FileType::Text(content) = &self.data.get(file).expect("file doesn't exist") {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(format!("{}/{}", &self.file_path, file)).expect("failed to open file");
-
+    pub fn is_folder(&self, path: &str) -> bool {
+        let prefix = format!("{}/", path);
+        self.data.keys().any(|key| key.starts_with(&prefix))
+    }
 
-            if let Some(content) = &content.content {
-                writeln!(&mut file, "{}", content).expect("failed to write file");
+    pub fn save(&mut self, file: &str) -> io::Result<()> {
+        if let FileType::Text(content) = self.data.get_mut(file).expect("file doesn't exist") {
+            if let Some(text) = &content.content {
+                write_contents(&content.path, text)?;
+                content.dirty = false;
+                content.info = FileInfo::read(&content.path);
+                self.index.update(file, text.as_bytes(), content.info);
             }
         }
+        Ok(())
+    }
+
+    pub fn info(&self, key: &str) -> FileInfo {
+        self.data.get(key).map(|f| f.info()).unwrap_or_default()
     }
 
     pub fn insert(&mut self, key: String, value: String) {
-        self.data.insert(key.clone(), FileType::Text(TextFile { path: key.clone(), content: Some(value), dirty: true }));
+        let path = self.disk_path(&key);
+        let read_only = self.is_read_only(&key);
+        let info = self.data.get(&key).map(|f| f.info()).unwrap_or_default();
+        self.data.insert(key, FileType::Text(TextFile { path, content: Some(value), dirty: true, read_only, info }));
+    }
+
+    // Adds an entry for a file that already exists on disk, without loading it
+    pub fn insert_existing(&mut self, key: String, path: PathBuf) {
+        let info = FileInfo::read(&path);
+        self.data.insert(key, FileType::Text(TextFile { path, content: None, dirty: false, read_only: false, info }));
+    }
+
+    pub fn is_read_only(&self, key: &str) -> bool {
+        match self.data.get(key) {
+            Some(FileType::Text(file)) => file.read_only,
+            _ => false,
+        }
+    }
+
+    pub fn mark_saved(&mut self, key: &str) {
+        if let Some(file) = self.data.get_mut(key) {
+            file.set_dirty(false);
+        }
     }
 
     pub fn mark_dirty(&mut self, key: String) {
@@ -194,4 +423,193 @@ impl Database {
     fn delete(&mut self, key: &str) -> Option<FileType> {
         self.data.remove(key)
     }
-}
\ No newline at end of file
+}
+
+fn write_contents(path: &Path, content: &str) -> io::Result<()> {
+    // Ensure the parent directory exists
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    // Open the file with write, truncate, and create options
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)?;
+
+    file.write_all(content.as_bytes())
+}
+
+// A new file in the temp folder with a name no other save is using. It's
+// created here rather than opened, so nothing already at that path is written to.
+fn create_temp() -> io::Result<(PathBuf, File)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let name = format!("text-editor-{}-{}.tmp", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Whether a file can't be written, or an error if it can't be read. Asked
+// without opening the file, since opening it to write shows up as a change
+// to anything watching the folder. Files marked read-only count as such even
+// for users who could write them anyway.
+#[cfg(unix)]
+fn read_only(path: &Path, meta: &Metadata) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: access only reads the path, which lives until after the calls
+    let allowed = |mode| unsafe { libc::access(path.as_ptr(), mode) } == 0;
+    if !allowed(libc::R_OK) {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(!allowed(libc::W_OK) || meta.permissions().readonly())
+}
+
+#[cfg(not(unix))]
+fn read_only(_path: &Path, meta: &Metadata) -> io::Result<bool> {
+    Ok(meta.permissions().readonly())
+}
+
+// The paths only ever reach the elevated PowerShell as base64, so nothing in a
+// file name can end a quoted string and run as a command
+#[cfg(target_os = "windows")]
+fn elevated_copy_command(from: &Path, to: &Path) -> Command {
+    use std::os::windows::ffi::OsStrExt;
+    let path = |path: &Path| format!(
+        "[Text.Encoding]::Unicode.GetString([Convert]::FromBase64String('{}'))",
+        utf16_base64(path.as_os_str().encode_wide())
+    );
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'; Copy-Item -LiteralPath ({}) -Destination ({}) -Force",
+        path(from),
+        path(to)
+    );
+    let mut command = Command::new("powershell");
+    command.args([
+        "-NoProfile",
+        "-Command",
+        &format!(
+            "$p = Start-Process powershell -Verb RunAs -Wait -PassThru -ArgumentList '-NoProfile','-EncodedCommand','{}'; exit $p.ExitCode",
+            utf16_base64(script.encode_utf16())
+        ),
+    ]);
+    command
+}
+
+// What PowerShell expects for -EncodedCommand and Encoding.Unicode
+#[cfg(target_os = "windows")]
+fn utf16_base64(text: impl Iterator<Item = u16>) -> String {
+    base64(&text.flat_map(u16::to_le_bytes).collect::<Vec<_>>())
+}
+
+#[cfg(target_os = "windows")]
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// The paths are handed to the script as arguments and quoted by AppleScript
+#[cfg(target_os = "macos")]
+fn elevated_copy_command(from: &Path, to: &Path) -> Command {
+    let mut command = Command::new("osascript");
+    command.args([
+        "-e", "on run argv",
+        "-e", "do shell script \"cp \" & quoted form of item 1 of argv & \" \" & quoted form of item 2 of argv with administrator privileges",
+        "-e", "end run",
+    ]);
+    command.arg(from).arg(to);
+    command
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn elevated_copy_command(from: &Path, to: &Path) -> Command {
+    let mut command = Command::new("pkexec");
+    command.arg("cp").arg(from).arg(to);
+    command
+}
+
+#[cfg(test)]
+pub mod test_support {
+    use std::fs::{create_dir_all, remove_dir_all};
+    use super::Database;
+
+    // A workspace in an empty folder of its own under the temp folder
+    pub fn workspace(name: &str) -> Database {
+        let dir = std::env::temp_dir().join(format!("text-editor-test-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        Database::new(dir.to_str().unwrap(), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::workspace;
+
+    fn with_keys(name: &str, keys: &[&str]) -> Database {
+        let mut db = workspace(name);
+        for key in keys {
+            db.insert(key.to_string(), String::new());
+        }
+        db
+    }
+
+    #[test]
+    fn unique_key_keeps_the_extension() {
+        let db = with_keys("unique-ext", &["notes.txt", "notes 1.txt", "dir/a.b.md"]);
+        assert_eq!(db.get_unique_key("fresh.txt"), "fresh.txt");
+        assert_eq!(db.get_unique_key("notes.txt"), "notes 2.txt");
+        assert_eq!(db.get_unique_key("dir/a.b.md"), "dir/a.b 1.md");
+    }
+
+    #[test]
+    fn unique_key_without_an_extension() {
+        let db = with_keys("unique-bare", &["README", ".gitignore", "v1.0/x"]);
+        assert_eq!(db.get_unique_key("README"), "README 1");
+        assert_eq!(db.get_unique_key(".gitignore"), ".gitignore 1");
+        // The dot in a folder name isn't an extension
+        assert_eq!(db.get_unique_key("v1.0/x"), "v1.0/x 1");
+    }
+
+    #[test]
+    fn unique_key_steps_around_folders() {
+        let db = with_keys("unique-folder", &["docs/a.txt", "docs 1/b.txt"]);
+        assert_eq!(db.get_unique_key("docs"), "docs 2");
+        assert_eq!(db.get_unique_key("doc"), "doc");
+    }
+
+    #[test]
+    fn files_marked_read_only_load_as_read_only() {
+        let mut db = workspace("read-only");
+        let dir = Path::new(&db.current_dir()).to_path_buf();
+        std::fs::write(dir.join("locked.txt"), "l").unwrap();
+        std::fs::write(dir.join("open.txt"), "o").unwrap();
+        let mut permissions = metadata(dir.join("locked.txt")).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(dir.join("locked.txt"), permissions).unwrap();
+
+        db.load().unwrap();
+        assert!(db.is_read_only("locked.txt"));
+        assert!(!db.is_read_only("open.txt"));
+    }
+}
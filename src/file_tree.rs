@@ -0,0 +1,514 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use slint::{Model, ModelNotify, ModelTracker, SharedString};
+use crate::cache::Database;
+use crate::file_types::{FileInfo, FileType};
+use crate::FileTreeItemData;
+use crate::sorting::{compare_entries, latest};
+
+struct Node {
+    name: String,
+    parent: String,
+    children: Vec<String>,
+    folder: bool,
+    // Folders add up everything below them so they can be sorted by size or date
+    info: FileInfo,
+    item: FileTreeItemData
+}
+
+// Backs the file tree with an actual tree. Only the rows that are visible
+// (every ancestor expanded) are kept in `rows`, and changes are reported to
+// Slint as row insertions and removals so the view keeps its scroll position.
+pub struct FileTreeModel {
+    nodes: RefCell<HashMap<String, Node>>,
+    rows: RefCell<Vec<String>>,
+    notify: ModelNotify
+}
+
+impl FileTreeModel {
+    pub fn new() -> Self {
+        let model = FileTreeModel {
+            nodes: RefCell::new(HashMap::new()),
+            rows: RefCell::new(Vec::new()),
+            notify: ModelNotify::default()
+        };
+        model.nodes.borrow_mut().insert(String::new(), Node::root());
+        model
+    }
+
+    // Full rebuild, for when the whole workspace, the sorting or the filter changed
+    pub fn rebuild(&self, db: &Database) {
+        {
+            let mut nodes = self.nodes.borrow_mut();
+            nodes.clear();
+            nodes.insert(String::new(), Node::root());
+            for key in db.visible_keys() {
+                add_path(&mut nodes, db, &key);
+            }
+            sum_folders(&mut nodes, "");
+            sort_children(&mut nodes, db, "");
+        }
+        let mut rows = Vec::new();
+        self.visible_subtree(db, "", &mut rows);
+        *self.rows.borrow_mut() = rows;
+        self.notify.reset();
+    }
+
+    pub fn insert_key(&self, db: &Database, key: &str) {
+        if !db.matches_filter(key) || self.nodes.borrow().contains_key(key) {
+            return;
+        }
+        // The highest node that didn't exist yet, everything new hangs below it
+        let top = {
+            let nodes = self.nodes.borrow();
+            let mut top = key.to_string();
+            while let Some(parent) = parent_of(&top) {
+                if nodes.contains_key(&parent) { break; }
+                top = parent;
+            }
+            top
+        };
+        {
+            let mut nodes = self.nodes.borrow_mut();
+            add_path(&mut nodes, db, key);
+            sum_folders(&mut nodes, &top);
+            let parent = nodes[&top].parent.clone();
+            let mut siblings = std::mem::take(&mut nodes.get_mut(&parent).unwrap().children);
+            siblings.retain(|s| *s != top);
+            let position = siblings.partition_point(|s| compare_nodes(&nodes, db, s, &top) == Ordering::Less);
+            siblings.insert(position, top.clone());
+            nodes.get_mut(&parent).unwrap().children = siblings;
+        }
+
+        let parent = self.nodes.borrow()[&top].parent.clone();
+        if self.shows_children(db, &parent) {
+            let mut new_rows = vec![top.clone()];
+            self.visible_subtree(db, &top, &mut new_rows);
+            let index = self.row_index_for(&parent, &top);
+            let count = new_rows.len();
+            self.rows.borrow_mut().splice(index..index, new_rows);
+            self.notify.row_added(index, count);
+        }
+        self.update_ancestors(db, parent);
+    }
+
+    pub fn remove_key(&self, db: &Database, key: &str) {
+        // Take empty folders along with their last file, but never the root
+        let top = {
+            let nodes = self.nodes.borrow();
+            if !nodes.contains_key(key) { return; }
+            let mut top = key.to_string();
+            while let Some(parent) = parent_of(&top) {
+                if parent.is_empty() || nodes[&parent].children.len() > 1 { break; }
+                top = parent;
+            }
+            top
+        };
+
+        let position = self.rows.borrow().iter().position(|row| *row == top);
+        if let Some(index) = position {
+            let count = self.subtree_rows(index);
+            self.rows.borrow_mut().drain(index..index + count);
+            self.notify.row_removed(index, count);
+        }
+
+        let parent = {
+            let mut nodes = self.nodes.borrow_mut();
+            let parent = nodes[&top].parent.clone();
+            nodes.get_mut(&parent).unwrap().children.retain(|c| *c != top);
+            remove_subtree(&mut nodes, &top);
+            parent
+        };
+        self.update_ancestors(db, parent);
+    }
+
+    // Adds up the folders above a change again. When sorting by size or date
+    // that can move each of them among its siblings.
+    fn update_ancestors(&self, db: &Database, mut path: String) {
+        while !path.is_empty() {
+            {
+                let mut nodes = self.nodes.borrow_mut();
+                let Some(node) = nodes.get(&path) else { return };
+                let total = node.children.iter().map(|child| nodes[child].info).fold(FileInfo::default(), add_info);
+                nodes.get_mut(&path).unwrap().info = total;
+            }
+            self.reposition(db, &path);
+            path = parent_of(&path).unwrap_or_default();
+        }
+    }
+
+    // Moves a node, and its rows if they're shown, to where it sorts now
+    fn reposition(&self, db: &Database, path: &str) {
+        let parent = {
+            let mut nodes = self.nodes.borrow_mut();
+            let Some(parent) = nodes.get(path).map(|node| node.parent.clone()) else { return };
+            let mut siblings = std::mem::take(&mut nodes.get_mut(&parent).unwrap().children);
+            let old = siblings.iter().position(|s| s == path);
+            siblings.retain(|s| s != path);
+            let position = siblings.partition_point(|s| compare_nodes(&nodes, db, s, path) == Ordering::Less);
+            siblings.insert(position, path.to_string());
+            nodes.get_mut(&parent).unwrap().children = siblings;
+            if old == Some(position) {
+                return;
+            }
+            parent
+        };
+        let Some(index) = self.row_of(path) else { return };
+        let count = self.subtree_rows(index);
+        let moved = self.rows.borrow_mut().drain(index..index + count).collect::<Vec<_>>();
+        self.notify.row_removed(index, count);
+        let index = self.row_index_for(&parent, path);
+        self.rows.borrow_mut().splice(index..index, moved);
+        self.notify.row_added(index, count);
+    }
+
+    // Shows or hides a folder's children after its collapsed state changed
+    pub fn toggle(&self, db: &Database, folder: &str) {
+        let position = self.rows.borrow().iter().position(|row| row == folder);
+        let Some(index) = position else { return };
+        let shown = self.subtree_rows(index) - 1;
+        if shown > 0 {
+            self.rows.borrow_mut().drain(index + 1..index + 1 + shown);
+            self.notify.row_removed(index + 1, shown);
+        }
+        if self.shows_children(db, folder) {
+            let mut new_rows = Vec::new();
+            self.visible_subtree(db, folder, &mut new_rows);
+            let count = new_rows.len();
+            self.rows.borrow_mut().splice(index + 1..index + 1, new_rows);
+            self.notify.row_added(index + 1, count);
+        }
+    }
+
+    // Re-reads a file's flags and metadata from the database
+    pub fn refresh_key(&self, db: &Database, key: &str) {
+        if let Some(node) = self.nodes.borrow_mut().get_mut(key) {
+            let open = node.item.open;
+            node.item = make_item(db, key, node.folder, node.item.ident);
+            node.item.open = open;
+            node.info = db.info(key);
+        }
+        self.notify_changed(|row| row == key);
+        self.reposition(db, key);
+        self.update_ancestors(db, parent_of(key).unwrap_or_default());
+    }
+
+    pub fn set_open(&self, key: Option<&str>) {
+        let changed = self.update_items(|path, item| {
+            let open = key == Some(path);
+            let changed = item.open != open;
+            item.open = open;
+            changed
+        });
+        self.notify_changed(|row| changed.iter().any(|c| c == row));
+    }
+
+    pub fn update_selection(&self, db: &Database) {
+        let changed = self.update_items(|path, item| {
+            let selected = db.selection.iter().any(|s| s == path);
+            let changed = item.selected != selected;
+            item.selected = selected;
+            changed
+        });
+        self.notify_changed(|row| changed.iter().any(|c| c == row));
+    }
+
+    pub fn path_at(&self, row: usize) -> Option<String> {
+        self.rows.borrow().get(row).cloned()
+    }
+
+    pub fn row_of(&self, path: &str) -> Option<usize> {
+        self.rows.borrow().iter().position(|row| row == path)
+    }
+
+    fn update_items(&self, mut update: impl FnMut(&str, &mut FileTreeItemData) -> bool) -> Vec<String> {
+        self.nodes.borrow_mut().iter_mut()
+            .filter_map(|(path, node)| if update(path, &mut node.item) { Some(path.clone()) } else { None })
+            .collect()
+    }
+
+    fn notify_changed(&self, matches: impl Fn(&str) -> bool) {
+        let changed = self.rows.borrow().iter().enumerate()
+            .filter(|(_, row)| matches(row))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        for row in changed {
+            self.notify.row_changed(row);
+        }
+    }
+
+    fn shows_children(&self, db: &Database, folder: &str) -> bool {
+        if folder.is_empty() {
+            return true;
+        }
+        // A filter expands every folder on the way to a match without touching the saved collapsed state
+        let expanded = db.is_filtering() || !db.is_collapsed(folder);
+        expanded && self.rows.borrow().iter().any(|row| row == folder)
+    }
+
+    fn visible_subtree(&self, db: &Database, path: &str, out: &mut Vec<String>) {
+        if !path.is_empty() && !db.is_filtering() && db.is_collapsed(path) {
+            return;
+        }
+        let children = self.nodes.borrow().get(path).map(|n| n.children.clone()).unwrap_or_default();
+        for child in children {
+            out.push(child.clone());
+            self.visible_subtree(db, &child, out);
+        }
+    }
+
+    // Number of rows the node at `index` takes up, itself included
+    fn subtree_rows(&self, index: usize) -> usize {
+        let rows = self.rows.borrow();
+        let prefix = format!("{}/", rows[index]);
+        1 + rows[index + 1..].iter().take_while(|row| row.starts_with(&prefix)).count()
+    }
+
+    // Where a new child goes: right after its previous sibling's rows
+    fn row_index_for(&self, parent: &str, child: &str) -> usize {
+        let previous = {
+            let nodes = self.nodes.borrow();
+            let siblings = &nodes[parent].children;
+            let position = siblings.iter().position(|s| s == child).unwrap_or(0);
+            if position == 0 { None } else { Some(siblings[position - 1].clone()) }
+        };
+        match previous {
+            Some(sibling) => {
+                let index = self.row_of(&sibling).unwrap_or(0);
+                index + self.subtree_rows(index)
+            }
+            None if parent.is_empty() => 0,
+            None => self.row_of(parent).map(|i| i + 1).unwrap_or(0),
+        }
+    }
+}
+
+impl Model for FileTreeModel {
+    type Data = FileTreeItemData;
+
+    fn row_count(&self) -> usize {
+        self.rows.borrow().len()
+    }
+
+    fn row_data(&self, row: usize) -> Option<Self::Data> {
+        let rows = self.rows.borrow();
+        let nodes = self.nodes.borrow();
+        let mut item = nodes.get(rows.get(row)?)?.item.clone();
+        item.index = row as i32;
+        Some(item)
+    }
+
+    fn set_row_data(&self, row: usize, data: Self::Data) {
+        let Some(path) = self.path_at(row) else { return };
+        if let Some(node) = self.nodes.borrow_mut().get_mut(&path) {
+            node.item = data;
+        }
+        self.notify.row_changed(row);
+    }
+
+    fn model_tracker(&self) -> &dyn ModelTracker {
+        &self.notify
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Node {
+    fn root() -> Self {
+        Node {
+            name: String::new(),
+            parent: String::new(),
+            children: Vec::new(),
+            folder: true,
+            info: FileInfo::default(),
+            item: FileTreeItemData::default()
+        }
+    }
+}
+
+fn parent_of(path: &str) -> Option<String> {
+    if path.is_empty() {
+        return None;
+    }
+    Some(path.rfind('/').map(|i| path[..i].to_string()).unwrap_or_default())
+}
+
+// Adds a key and any missing folders above it. New children are appended,
+// callers sort them into place.
+fn add_path(nodes: &mut HashMap<String, Node>, db: &Database, key: &str) {
+    let parts = key.split('/').collect::<Vec<_>>();
+    let mut path = String::new();
+    for (depth, part) in parts.iter().enumerate() {
+        let parent = path.clone();
+        if !path.is_empty() { path.push('/'); }
+        path.push_str(part);
+        if nodes.contains_key(&path) { continue; }
+
+        let folder = depth < parts.len() - 1;
+        let node = Node {
+            name: part.to_string(),
+            parent: parent.clone(),
+            children: Vec::new(),
+            folder,
+            info: if folder { FileInfo::default() } else { db.info(&path) },
+            item: make_item(db, &path, folder, depth as i32)
+        };
+        nodes.insert(path.clone(), node);
+        nodes.get_mut(&parent).unwrap().children.push(path.clone());
+    }
+}
+
+fn make_item(db: &Database, path: &str, folder: bool, depth: i32) -> FileTreeItemData {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let selected = db.selection.iter().any(|s| s == path);
+    if folder {
+        return FileTreeItemData {
+            name: SharedString::from(name),
+            index: 0,
+            full_path: SharedString::from(path),
+            ident: depth,
+            open: false,
+            r#type: SharedString::from("folder"),
+            read_only: false,
+            size: SharedString::new(),
+            modified: SharedString::new(),
+            reason: SharedString::new(),
+            selected
+        };
+    }
+    let reason = match db.data.get(path) {
+        Some(FileType::Inaccessible(entry)) => Some(entry.reason.as_str()),
+        _ => None,
+    };
+    let info = db.info(path);
+    FileTreeItemData {
+        name: SharedString::from(name),
+        index: 0,
+        full_path: SharedString::from(path),
+        ident: depth,
+        open: false,
+        r#type: SharedString::from(if reason.is_some() { "inaccessible" } else { "file" }),
+        read_only: db.is_read_only(path),
+        size: SharedString::from(info.size_label()),
+        modified: SharedString::from(info.modified_label()),
+        reason: SharedString::from(reason.unwrap_or_default()),
+        selected
+    }
+}
+
+fn sum_folders(nodes: &mut HashMap<String, Node>, path: &str) -> FileInfo {
+    let node = &nodes[path];
+    if !node.folder {
+        return node.info;
+    }
+    let children = node.children.clone();
+    let mut total = FileInfo::default();
+    for child in children {
+        total = add_info(total, sum_folders(nodes, &child));
+    }
+    nodes.get_mut(path).unwrap().info = total;
+    total
+}
+
+fn add_info(total: FileInfo, info: FileInfo) -> FileInfo {
+    FileInfo {
+        size: total.size + info.size,
+        modified: latest(total.modified, info.modified),
+        created: latest(total.created, info.created)
+    }
+}
+
+fn sort_children(nodes: &mut HashMap<String, Node>, db: &Database, path: &str) {
+    let mut children = std::mem::take(&mut nodes.get_mut(path).unwrap().children);
+    children.sort_by(|a, b| compare_nodes(nodes, db, a, b));
+    for child in &children {
+        sort_children(nodes, db, child);
+    }
+    nodes.get_mut(path).unwrap().children = children;
+}
+
+fn compare_nodes(nodes: &HashMap<String, Node>, db: &Database, a: &str, b: &str) -> Ordering {
+    let (a, b) = (&nodes[a], &nodes[b]);
+    compare_entries((&a.name, a.folder, &a.info), (&b.name, b.folder, &b.info), db.sort_mode, db.folders_first)
+}
+
+fn remove_subtree(nodes: &mut HashMap<String, Node>, path: &str) {
+    if let Some(node) = nodes.remove(path) {
+        for child in node.children {
+            remove_subtree(nodes, &child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::cache::test_support::workspace;
+    use crate::file_types::TextFile;
+    use crate::sorting::SortMode;
+    use super::*;
+
+    fn add(db: &mut Database, key: &str, size: u64) {
+        let info = FileInfo { size, modified: None, created: None };
+        db.data.insert(key.to_string(), FileType::Text(TextFile { path: PathBuf::from(key), content: Some(String::new()), dirty: false, read_only: false, info }));
+    }
+
+    fn rows(model: &FileTreeModel) -> Vec<String> {
+        (0..model.row_count()).filter_map(|row| model.path_at(row)).collect()
+    }
+
+    #[test]
+    fn renaming_the_only_file_keeps_the_root() {
+        let mut db = workspace("tree-only-file");
+        add(&mut db, "a.txt", 1);
+        let model = FileTreeModel::new();
+        model.rebuild(&db);
+
+        db.rename("a.txt", "b.txt");
+        model.remove_key(&db, "a.txt");
+        assert!(rows(&model).is_empty());
+        model.insert_key(&db, "b.txt");
+        assert_eq!(rows(&model), ["b.txt"]);
+    }
+
+    #[test]
+    fn empty_folders_go_with_their_last_file() {
+        let mut db = workspace("tree-empty-folders");
+        add(&mut db, "a/b/c.txt", 1);
+        add(&mut db, "d.txt", 1);
+        let model = FileTreeModel::new();
+        model.rebuild(&db);
+        assert_eq!(rows(&model), ["a", "a/b", "a/b/c.txt", "d.txt"]);
+
+        db.remove("a/b/c.txt");
+        model.remove_key(&db, "a/b/c.txt");
+        assert_eq!(rows(&model), ["d.txt"]);
+    }
+
+    #[test]
+    fn folder_totals_follow_changes() {
+        let mut db = workspace("tree-totals");
+        db.sort_mode = SortMode::Size;
+        add(&mut db, "big/a.txt", 10);
+        add(&mut db, "small/b.txt", 1);
+        let model = FileTreeModel::new();
+        model.rebuild(&db);
+        assert_eq!(rows(&model), ["big", "big/a.txt", "small", "small/b.txt"]);
+
+        add(&mut db, "small/c.txt", 100);
+        model.insert_key(&db, "small/c.txt");
+        assert_eq!(rows(&model), ["small", "small/c.txt", "small/b.txt", "big", "big/a.txt"]);
+
+        db.remove("small/c.txt");
+        model.remove_key(&db, "small/c.txt");
+        assert_eq!(rows(&model), ["big", "big/a.txt", "small", "small/b.txt"]);
+
+        add(&mut db, "small/b.txt", 50);
+        model.refresh_key(&db, "small/b.txt");
+        assert_eq!(rows(&model), ["small", "small/b.txt", "big", "big/a.txt"]);
+    }
+}
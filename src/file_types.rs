@@ -1,28 +1,50 @@
-use std::fs::File;
+use std::fs::{File, Metadata};
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use chrono::{DateTime, Local};
+use log::{debug, warn};
 
 pub enum FileType {
     Text(TextFile),
     Image(ImageFile),
-
+    Inaccessible(InaccessibleEntry),
 }
 
 pub struct TextFile {
-    pub path: String,
+    pub path: PathBuf,
     pub content: Option<String>,
-    pub dirty: bool
+    pub dirty: bool,
+    pub read_only: bool,
+    pub info: FileInfo
+}
+
+// What the tree needs to know about a file on disk for sorting and display.
+#[derive(Clone, Copy, Default)]
+pub struct FileInfo {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub created: Option<SystemTime>
 }
 
 pub struct ImageFile {
-    pub path: String,
+    pub path: PathBuf,
     pub dirty: bool
 }
 
+// A file or directory found while scanning that couldn't be read.
+// It is kept so the tree can show it instead of silently dropping it.
+pub struct InaccessibleEntry {
+    pub path: PathBuf,
+    pub reason: String
+}
+
 impl FileType {
     pub fn display(&self) {
         match self {
             FileType::Text(file) => file.display(),
             FileType::Image(file) => file.display(),
+            FileType::Inaccessible(entry) => entry.display(),
         }
     }
 
@@ -30,14 +52,16 @@ impl FileType {
         match self {
             FileType::Text(file) => file.set_dirty(dirty),
             FileType::Image(file) => file.set_dirty(dirty),
+            FileType::Inaccessible(_) => {}
         }
     }
 
 
-    pub fn get_path(&mut self) -> String {
+    pub fn get_path(&mut self) -> PathBuf {
         match self {
             FileType::Text(file) => file.path.clone(),
             FileType::Image(file) => file.path.clone(),
+            FileType::Inaccessible(entry) => entry.path.clone(),
         }
     }
 
@@ -45,9 +69,49 @@ impl FileType {
         match self {
             FileType::Text(file) => file.is_dirty(),
             FileType::Image(file) => file.is_dirty(),
+            FileType::Inaccessible(_) => false,
+        }
+    }
+
+    pub fn info(&self) -> FileInfo {
+        match self {
+            FileType::Text(file) => file.info,
+            _ => FileInfo::default(),
+        }
+    }
+}
+impl FileInfo {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        FileInfo {
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            created: meta.created().ok()
+        }
+    }
+
+    pub fn read(path: &Path) -> Self {
+        std::fs::metadata(path).map(|meta| FileInfo::from_metadata(&meta)).unwrap_or_default()
+    }
+
+    pub fn size_label(&self) -> String {
+        let units = ["B", "KB", "MB", "GB"];
+        let mut size = self.size as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 { format!("{} {}", self.size, units[0]) } else { format!("{:.1} {}", size, units[unit]) }
+    }
+
+    pub fn modified_label(&self) -> String {
+        match self.modified {
+            Some(time) => DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M").to_string(),
+            None => String::new(),
         }
     }
 }
+
 impl TextFile {
     fn set_dirty(&mut self, dirty: bool) {
         self.dirty = dirty;
@@ -57,7 +121,7 @@ impl TextFile {
     }
 
     fn display(&self) {
-        println!("Displaying text file: {:?}", self.content);
+        debug!("Displaying text file {}", self.path.display());
     }
 
     pub fn get_contents(&mut self) -> String {
@@ -65,8 +129,9 @@ impl TextFile {
             return content.clone();
         } else {
             let mut content = String::new();
-            let mut file = File::open(&self.path).expect(format!("File {} not found", &self.path.clone()).as_str());
-            let r = file.read_to_string(&mut content);
+            let file = File::open(&self.path);
+            if file.is_err() { return String::from("???"); }
+            let r = file.unwrap().read_to_string(&mut content);
             if r.is_err() { return String::from("???"); }
 
             self.content = Some(content.clone());
@@ -83,6 +148,12 @@ impl ImageFile {
         self.dirty
     }
     fn display(&self) {
-        println!("Displaying image file from path: {}", self.path);
+        debug!("Displaying image file from path: {}", self.path.display());
+    }
+}
+
+impl InaccessibleEntry {
+    pub fn display(&self) {
+        warn!("Can't display {}: {}", self.path.display(), self.reason);
     }
 }
@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use regex::Regex;
+use slint::{SharedString, VecModel, Weak};
+use crate::AppWindow;
+use crate::notifications::Notifier;
+use crate::search::{apply_edits, build_pattern, find_all, line_starts, locate, replacement_at};
+
+// The find bar's query. Matches are looked up again from the editor's text on
+// every step, so they never go stale while the file is being edited.
+#[derive(Default)]
+struct FindState {
+    pattern: Option<Regex>,
+    regex: bool,
+    // Start of the selected match
+    current: Option<usize>
+}
+
+impl FindState {
+    // Selects the match at `index` and updates the count and markers
+    fn show(&mut self, ui: &AppWindow, text: &str, matches: &[(usize, usize)], index: usize) {
+        let starts = line_starts(text);
+        let markers = matches.iter()
+            .map(|&(start, _)| locate(text, &starts, start).0 as f32 / starts.len() as f32)
+            .collect::<Vec<_>>();
+        ui.set_find_markers(Rc::new(VecModel::from(markers)).into());
+
+        let Some(&(start, end)) = matches.get(index) else {
+            self.current = None;
+            ui.invoke_set_find_status(0, 0, true);
+            return;
+        };
+        self.current = Some(start);
+        let (line, _) = locate(text, &starts, start);
+        ui.invoke_scroll_to_line(line as i32 + 1, starts.len() as i32);
+        ui.invoke_highlight(start as i32, end as i32);
+        ui.invoke_set_find_status(index as i32 + 1, matches.len() as i32, true);
+    }
+
+    fn matches(&self, text: &str) -> Vec<(usize, usize)> {
+        self.pattern.as_ref().map(|pattern| find_all(pattern, text)).unwrap_or_default()
+    }
+
+    fn step(&mut self, ui: &AppWindow, forward: bool) {
+        let text = ui.invoke_get_current_box();
+        let matches = self.matches(&text);
+        if matches.is_empty() {
+            return self.show(ui, &text, &matches, 0);
+        }
+        // Wraps around at either end of the file
+        let index = match (self.current, forward) {
+            (None, true) => 0,
+            (None, false) => matches.len() - 1,
+            (Some(at), true) => matches.iter().position(|m| m.0 > at).unwrap_or(0),
+            (Some(at), false) => matches.iter().rposition(|m| m.0 < at).unwrap_or(matches.len() - 1),
+        };
+        self.show(ui, &text, &matches, index);
+    }
+}
+
+pub fn on_find_bar(notifier: Rc<Notifier>, weak: Weak<AppWindow>) {
+    let find = Rc::new(RefCell::new(FindState::default()));
+    let ui = weak.unwrap();
+
+    let (ui_handle, find_clone) = (weak.clone(), Rc::clone(&find));
+    ui.on_find_in_file(move |query: SharedString, match_case: bool, whole_word: bool, regex: bool| {
+        let ui = ui_handle.unwrap();
+        let mut find = find_clone.borrow_mut();
+        find.regex = regex;
+        find.pattern = None;
+        if query.is_empty() {
+            ui.set_find_markers(Rc::new(VecModel::<f32>::default()).into());
+            ui.invoke_set_find_status(0, 0, true);
+            return;
+        }
+        let Some(pattern) = build_pattern(query.as_str(), regex, match_case, whole_word) else {
+            ui.set_find_markers(Rc::new(VecModel::<f32>::default()).into());
+            ui.invoke_set_find_status(0, 0, false);
+            return;
+        };
+        find.pattern = Some(pattern);
+        // Typing more of the query keeps the selection where it was if it still matches there
+        let text = ui.invoke_get_current_box();
+        let matches = find.matches(&text);
+        let from = find.current.unwrap_or(0);
+        let index = matches.iter().position(|m| m.0 >= from).unwrap_or(0);
+        find.show(&ui, &text, &matches, index);
+    });
+
+    let (ui_handle, find_clone) = (weak.clone(), Rc::clone(&find));
+    ui.on_find_step(move |forward: bool| find_clone.borrow_mut().step(&ui_handle.unwrap(), forward));
+
+    let ui_handle = weak.clone();
+    ui.on_replace_in_file(move |replacement: SharedString, all: bool| {
+        let ui = ui_handle.unwrap();
+        let mut find = find.borrow_mut();
+        let Some(pattern) = find.pattern.clone() else { return };
+        let text = ui.invoke_get_current_box();
+        let matches = find.matches(&text);
+
+        let edits = if all {
+            matches.iter()
+                .filter_map(|&(start, end)| replacement_at(&pattern, &text, start, end, replacement.as_str(), find.regex).map(|r| (start, end, r)))
+                .collect::<Vec<_>>()
+        } else {
+            // Replacing only ever touches the selected match, the first press
+            // just selects one
+            let Some(&(start, end)) = matches.iter().find(|m| Some(m.0) == find.current) else {
+                return find.step(&ui, true);
+            };
+            replacement_at(&pattern, &text, start, end, replacement.as_str(), find.regex).map(|r| (start, end, r)).into_iter().collect()
+        };
+        if edits.is_empty() {
+            return;
+        }
+
+        let replaced = apply_edits(&text, &edits);
+        ui.invoke_set_current_box(SharedString::from(replaced.as_str()));
+        // Carries on after the replaced text, so a replacement that matches
+        // the query again isn't found straight away
+        let (start, _, inserted) = &edits[edits.len() - 1];
+        let after = start + inserted.len();
+        let matches = find.matches(&replaced);
+        let index = matches.iter().position(|m| m.0 >= after).unwrap_or(0);
+        find.show(&ui, &replaced, &matches, index);
+        if all {
+            notifier.info(format!("Replaced {} match{}", edits.len(), if edits.len() == 1 { "" } else { "es" }));
+        }
+    });
+}
@@ -0,0 +1,163 @@
+// Case-insensitive subsequence match: every character of the pattern has to
+// appear in the text, in order, but not necessarily next to each other.
+pub fn fuzzy_match(pattern: &str, text: &str) -> bool {
+    let mut text_chars = text.chars().flat_map(char::to_lowercase);
+    pattern.chars().flat_map(char::to_lowercase).all(|p| text_chars.any(|t| t == p))
+}
+
+pub fn substring_match(pattern: &str, text: &str) -> bool {
+    text.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+// Scoring in the spirit of fzf: every matched character is worth the same,
+// gaps cost a little, and matches right after a path separator, a word
+// boundary or a camelCase hump are worth more since that's where people
+// start typing a name from.
+const SCORE_MATCH: i32 = 16;
+const GAP_START: i32 = -3;
+const GAP_EXTENSION: i32 = -1;
+const BONUS_SEPARATOR: i32 = 9;
+const BONUS_BOUNDARY: i32 = 8;
+const BONUS_CAMEL: i32 = 7;
+const BONUS_CONSECUTIVE: i32 = 4;
+// A match in the file name itself beats one in the folders above it
+const BONUS_FILE_NAME: i32 = 2;
+
+pub struct FuzzyMatch {
+    pub score: i32,
+    // Char indices into the text, for highlighting
+    pub positions: Vec<usize>
+}
+
+// Best scoring alignment of `pattern` as a subsequence of `text`. Case is
+// ignored unless the pattern has an uppercase letter, like fzf's smart case.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<FuzzyMatch> {
+    let case_sensitive = pattern.chars().any(char::is_uppercase);
+    let fold = |c: char| if case_sensitive { c } else { c.to_lowercase().next().unwrap_or(c) };
+    let pattern = pattern.chars().filter(|c| !c.is_whitespace()).map(fold).collect::<Vec<_>>();
+    let original = text.chars().collect::<Vec<_>>();
+    let chars = original.iter().map(|&c| fold(c)).collect::<Vec<_>>();
+    let (n, m) = (pattern.len(), chars.len());
+    if n == 0 {
+        return Some(FuzzyMatch { score: 0, positions: Vec::new() });
+    }
+    if n > m || !fuzzy_match_chars(&pattern, &chars) {
+        return None;
+    }
+
+    let name_start = original.iter().rposition(|&c| c == '/').map(|i| i + 1).unwrap_or(0);
+    let bonus = (0..m).map(|j| {
+        let position = bonus_at(if j == 0 { None } else { Some(original[j - 1]) }, original[j]);
+        position + if j >= name_start { BONUS_FILE_NAME } else { 0 }
+    }).collect::<Vec<_>>();
+
+    // score[i][j] is the best score with pattern[i] matched at text[j], and
+    // from[i][j] is where pattern[i - 1] was matched on that path
+    const NONE: i32 = i32::MIN / 2;
+    let mut score = vec![vec![NONE; m]; n];
+    let mut from = vec![vec![0usize; m]; n];
+    for i in 0..n {
+        // Best way to arrive at j through a gap, and where it came from
+        let mut gap = (NONE, 0);
+        for j in i..m {
+            if i > 0 && j >= 2 {
+                let extended = gap.0 + GAP_EXTENSION;
+                let started = score[i - 1][j - 2] + GAP_START;
+                gap = if started >= extended { (started, j - 2) } else { (extended, gap.1) };
+            }
+            if chars[j] != pattern[i] {
+                continue;
+            }
+            if i == 0 {
+                score[i][j] = SCORE_MATCH + bonus[j] * 2;
+                continue;
+            }
+            let consecutive = score[i - 1][j - 1] + BONUS_CONSECUTIVE;
+            let (previous, k) = if consecutive >= gap.0 { (consecutive, j - 1) } else { gap };
+            // Anything near NONE grew out of an unmatched cell
+            if previous > NONE / 2 {
+                score[i][j] = previous + SCORE_MATCH + bonus[j];
+                from[i][j] = k;
+            }
+        }
+    }
+
+    let (mut j, best) = score[n - 1].iter().enumerate().max_by_key(|(j, s)| (**s, std::cmp::Reverse(*j)))?;
+    let best = *best;
+    if best <= NONE / 2 {
+        return None;
+    }
+    let mut positions = vec![0; n];
+    for i in (0..n).rev() {
+        positions[i] = j;
+        j = from[i][j];
+    }
+    Some(FuzzyMatch { score: best, positions })
+}
+
+fn fuzzy_match_chars(pattern: &[char], text: &[char]) -> bool {
+    let mut text = text.iter();
+    pattern.iter().all(|p| text.any(|t| t == p))
+}
+
+fn bonus_at(previous: Option<char>, current: char) -> i32 {
+    match previous {
+        None => BONUS_BOUNDARY,
+        Some('/') | Some('\\') => BONUS_SEPARATOR,
+        Some(c) if c.is_whitespace() || matches!(c, '_' | '-' | '.') => BONUS_BOUNDARY,
+        Some(c) if c.is_lowercase() && current.is_uppercase() => BONUS_CAMEL,
+        Some(c) if c.is_alphabetic() && current.is_numeric() => BONUS_CAMEL,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(pattern: &str, text: &str) -> Option<Vec<usize>> {
+        fuzzy_score(pattern, text).map(|m| m.positions)
+    }
+
+    fn score(pattern: &str, text: &str) -> i32 {
+        fuzzy_score(pattern, text).expect("should match").score
+    }
+
+    #[test]
+    fn matches_only_subsequences() {
+        assert!(fuzzy_score("abc", "a_b_c").is_some());
+        assert!(fuzzy_score("acb", "abc").is_none());
+        assert!(fuzzy_score("abcd", "abc").is_none());
+        assert_eq!(positions("", "abc"), Some(vec![]));
+        // Spaces in the pattern are only there to help the eye
+        assert_eq!(positions("a c", "abc"), Some(vec![0, 2]));
+    }
+
+    #[test]
+    fn smart_case() {
+        assert!(fuzzy_score("readme", "README.md").is_some());
+        assert!(fuzzy_score("ReadMe", "readme.md").is_none());
+        assert!(fuzzy_score("ReadMe", "src/ReadMe.md").is_some());
+    }
+
+    #[test]
+    fn prefers_boundaries_and_the_file_name() {
+        // The later 'f' and 'b' start words, the earlier ones don't
+        assert_eq!(positions("fb", "xfxb foo_bar"), Some(vec![5, 9]));
+        assert_eq!(positions("fb", "fooBar"), Some(vec![0, 3]));
+        assert!(score("main", "src/main.rs") > score("main", "src/domain.rs"));
+        assert!(score("ab", "ab/x.txt") < score("ab", "x/ab.txt"));
+    }
+
+    #[test]
+    fn prefers_consecutive_matches() {
+        assert!(score("abc", "abcxxx") > score("abc", "axbxcx"));
+        assert_eq!(positions("abc", "axbxc abc"), Some(vec![6, 7, 8]));
+    }
+
+    #[test]
+    fn positions_are_char_indices() {
+        assert_eq!(positions("é", "aé"), Some(vec![1]));
+        assert_eq!(positions("ñb", "ñandú/b"), Some(vec![0, 6]));
+    }
+}
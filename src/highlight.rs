@@ -0,0 +1,190 @@
+use std::path::Path;
+use std::sync::OnceLock;
+use slint::SharedString;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+use crate::{StyledSpan, TokenKind};
+
+// Past this the file is shown as plain text, highlighting it would stall the editor
+const MAX_HIGHLIGHT_BYTES: usize = 1024 * 1024;
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+// Scope prefixes and what they're drawn as. A token takes the kind of its
+// innermost scope that's listed, so punctuation inside a string is a string.
+fn kinds() -> &'static [(Scope, TokenKind)] {
+    static KINDS: OnceLock<Vec<(Scope, TokenKind)>> = OnceLock::new();
+    KINDS.get_or_init(|| {
+        [
+            ("comment", TokenKind::Comment),
+            ("string", TokenKind::String),
+            ("markup.raw", TokenKind::String),
+            ("constant", TokenKind::Constant),
+            ("keyword", TokenKind::Keyword),
+            ("storage", TokenKind::Keyword),
+            ("entity.name.function", TokenKind::Function),
+            ("support.function", TokenKind::Function),
+            ("variable.function", TokenKind::Function),
+            ("entity.name", TokenKind::TypeName),
+            ("support.type", TokenKind::TypeName),
+            ("support.class", TokenKind::TypeName),
+            ("entity.other.inherited-class", TokenKind::TypeName),
+            ("markup.heading", TokenKind::Heading),
+            ("markup.bold", TokenKind::Heading),
+            ("markup.italic", TokenKind::Heading),
+            ("markup.underline.link", TokenKind::Function),
+        ]
+        .into_iter()
+        .filter_map(|(scope, kind)| Scope::new(scope).ok().map(|scope| (scope, kind)))
+        .collect()
+    })
+}
+
+fn kind(stack: &ScopeStack) -> TokenKind {
+    stack.as_slice().iter().rev()
+        .find_map(|scope| kinds().iter().find(|(prefix, _)| prefix.is_prefix_of(*scope)).map(|(_, kind)| *kind))
+        .unwrap_or(TokenKind::Plain)
+}
+
+// Picked by the file's extension, or its first line for scripts without one
+fn find_syntax(key: &str, text: &str) -> Option<&'static SyntaxReference> {
+    let set = syntaxes();
+    let extension = Path::new(key).extension().and_then(|e| e.to_str()).unwrap_or_default();
+    set.find_syntax_by_extension(extension)
+        .or_else(|| set.find_syntax_by_first_line(text.lines().next().unwrap_or_default()))
+        .filter(|syntax| syntax.name != set.find_syntax_plain_text().name)
+}
+
+// The parser's state where a line starts
+#[derive(Clone, PartialEq)]
+struct LineState {
+    parse: ParseState,
+    scopes: ScopeStack
+}
+
+impl LineState {
+    // Highlights one line, leaving the state where the next one starts
+    fn highlight(&mut self, line: &str) -> Vec<StyledSpan> {
+        let ops = self.parse.parse_line(line, syntaxes()).unwrap_or_default();
+        let mut spans: Vec<StyledSpan> = Vec::new();
+        let mut push = |text: &str, kind: TokenKind| {
+            let text = text.trim_end_matches(['\n', '\r']);
+            if text.is_empty() {
+                return;
+            }
+            match spans.last_mut() {
+                Some(last) if last.kind == kind => last.text = SharedString::from(format!("{}{}", last.text, text)),
+                _ => spans.push(StyledSpan { text: SharedString::from(text), kind }),
+            }
+        };
+        let mut at = 0;
+        for (index, op) in ops {
+            push(&line[at..index], kind(&self.scopes));
+            // A stack that doesn't line up only costs colors, not text
+            let _ = self.scopes.apply(&op);
+            at = index;
+        }
+        push(&line[at..], kind(&self.scopes));
+        spans
+    }
+}
+
+// Highlighting of the text in one editor pane. Edits only re-highlight from
+// the first changed line until the parser is back in the state it was in
+// before, so typing inside a function doesn't redo the whole file.
+struct Highlight {
+    lines: Vec<String>,
+    // One more than there are lines, the last is where the text ends
+    states: Vec<LineState>,
+    spans: Vec<Vec<StyledSpan>>
+}
+
+impl Highlight {
+    fn new(syntax: &SyntaxReference, text: &str) -> Self {
+        let start = LineState { parse: ParseState::new(syntax), scopes: ScopeStack::new() };
+        let mut highlight = Highlight { lines: Vec::new(), states: vec![start], spans: Vec::new() };
+        highlight.update(text);
+        highlight
+    }
+
+    fn update(&mut self, text: &str) {
+        let new_lines = LinesWithEndings::from(text).collect::<Vec<_>>();
+        let prefix = self.lines.iter().zip(&new_lines).take_while(|(a, b)| a == *b).count();
+        let longest = self.lines.len().min(new_lines.len()) - prefix;
+        let suffix = self.lines.iter().rev().zip(new_lines.iter().rev()).take(longest).take_while(|(a, b)| a == *b).count();
+        let removed = self.lines.len() - prefix - suffix;
+        let inserted = new_lines.len() - prefix - suffix;
+
+        self.lines.splice(prefix..prefix + removed, new_lines[prefix..prefix + inserted].iter().map(|line| line.to_string()));
+        // Lines after the change keep the state they used to start in, to
+        // tell when highlighting can stop
+        let start = self.states[prefix].clone();
+        self.states.splice(prefix..prefix + removed, (0..inserted).map(|_| start.clone()));
+        self.spans.splice(prefix..prefix + removed, (0..inserted).map(|_| Vec::new()));
+
+        let mut state = start;
+        let mut i = prefix;
+        while i < self.lines.len() && (i < prefix + inserted || self.states[i] != state) {
+            self.states[i] = state.clone();
+            self.spans[i] = state.highlight(&self.lines[i]);
+            i += 1;
+        }
+        if i == self.lines.len() {
+            self.states[i] = state;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pane {
+    Main,
+    Side
+}
+
+// Highlighting for what's shown in each pane
+#[derive(Default)]
+pub struct Highlights {
+    main: Option<Highlight>,
+    side: Option<Highlight>
+}
+
+impl Highlights {
+    fn pane(&mut self, pane: Pane) -> &mut Option<Highlight> {
+        match pane {
+            Pane::Main => &mut self.main,
+            Pane::Side => &mut self.side,
+        }
+    }
+
+    // Whether the text gets highlighted, it's shown plain otherwise
+    pub fn open(&mut self, pane: Pane, key: &str, text: &str) -> bool {
+        let highlight = find_syntax(key, text)
+            .filter(|_| text.len() <= MAX_HIGHLIGHT_BYTES)
+            .map(|syntax| Highlight::new(syntax, text));
+        let highlighted = highlight.is_some();
+        *self.pane(pane) = highlight;
+        highlighted
+    }
+
+    // Each line's spans, None for plain text
+    pub fn spans(&self, pane: Pane) -> Option<&[Vec<StyledSpan>]> {
+        let highlight = match pane {
+            Pane::Main => &self.main,
+            Pane::Side => &self.side,
+        };
+        highlight.as_ref().map(|h| h.spans.as_slice())
+    }
+
+    pub fn close(&mut self, pane: Pane) {
+        *self.pane(pane) = None;
+    }
+
+    pub fn update(&mut self, pane: Pane, text: &str) {
+        if let Some(highlight) = self.pane(pane) {
+            highlight.update(text);
+        }
+    }
+}
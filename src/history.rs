@@ -0,0 +1,333 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::time::{Duration, Instant};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::cache::Database;
+
+const HISTORY_FILE: &str = "history.json";
+// Typing that stops for longer than this starts a new undo step
+const TYPING_PAUSE: Duration = Duration::from_secs(1);
+// Undo steps kept for each file
+const MAX_STEPS: usize = 500;
+
+// `removed` replaced by `inserted` at byte offset `start`
+#[derive(Serialize, Deserialize, Clone)]
+struct Edit {
+    start: usize,
+    removed: String,
+    inserted: String
+}
+
+impl Edit {
+    // The smallest edit turning `before` into `after`, found by trimming what
+    // both have in common at either end
+    fn between(before: &str, after: &str) -> Option<Self> {
+        if before == after {
+            return None;
+        }
+        let mut prefix = before.bytes().zip(after.bytes()).take_while(|(a, b)| a == b).count();
+        while !before.is_char_boundary(prefix) || !after.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        let longest = (before.len() - prefix).min(after.len() - prefix);
+        let mut suffix = before.bytes().rev().zip(after.bytes().rev()).take(longest).take_while(|(a, b)| a == b).count();
+        while !before.is_char_boundary(before.len() - suffix) || !after.is_char_boundary(after.len() - suffix) {
+            suffix -= 1;
+        }
+        Some(Edit {
+            start: prefix,
+            removed: before[prefix..before.len() - suffix].to_string(),
+            inserted: after[prefix..after.len() - suffix].to_string()
+        })
+    }
+
+    fn inverse(&self) -> Edit {
+        Edit { start: self.start, removed: self.inserted.clone(), inserted: self.removed.clone() }
+    }
+
+    // None when the text no longer has what the edit expects to replace
+    fn apply(&self, text: &str) -> Option<String> {
+        let end = self.start + self.removed.len();
+        if text.get(self.start..end) != Some(self.removed.as_str()) {
+            return None;
+        }
+        Some(format!("{}{}{}", &text[..self.start], self.inserted, &text[end..]))
+    }
+
+    // Folds the next keystroke into this step if it carries on the same run
+    // of typing or deleting. A new line or a new word starts a new step.
+    fn merge(&mut self, next: &Edit) -> bool {
+        if self.removed.is_empty() && next.removed.is_empty() {
+            let new_word = self.inserted.ends_with(char::is_whitespace) && !next.inserted.starts_with(char::is_whitespace);
+            if next.start != self.start + self.inserted.len() || next.inserted.contains('\n') || new_word {
+                return false;
+            }
+            self.inserted.push_str(&next.inserted);
+            true
+        } else if self.inserted.is_empty() && next.inserted.is_empty() {
+            if next.start + next.removed.len() == self.start {
+                // Backspace
+                self.removed.insert_str(0, &next.removed);
+                self.start = next.start;
+            } else if next.start == self.start {
+                // Delete
+                self.removed.push_str(&next.removed);
+            } else {
+                return false;
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Undo and redo for one file's text, kept by the editor rather than the text
+// box so it survives switching files
+#[derive(Serialize, Deserialize, Default)]
+pub struct TextHistory {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    // Fingerprint of the text the history ends at, set when it's written to
+    // disk so a file changed outside the editor drops its old history
+    #[serde(default)]
+    checksum: Option<u64>,
+    // The text as of the last recorded change, the next one is diffed against it
+    #[serde(skip)]
+    text: Option<String>,
+    #[serde(skip)]
+    last_edit: Option<Instant>
+}
+
+impl TextHistory {
+    // Called whenever the file is put in an editor, so the first change made
+    // there has something to be compared with. Steps for text that changed
+    // behind the history's back, e.g. by a replace in files, are dropped.
+    pub fn sync(&mut self, text: &str) {
+        let changed = match (&self.text, self.checksum.take()) {
+            (Some(known), _) => known != text,
+            (None, Some(checksum)) => checksum != fingerprint(text),
+            (None, None) => false,
+        };
+        if changed {
+            self.undo.clear();
+            self.redo.clear();
+        }
+        self.text = Some(text.to_string());
+        self.last_edit = None;
+    }
+
+    pub fn record(&mut self, after: &str) {
+        let Some(before) = self.text.replace(after.to_string()) else { return };
+        let Some(edit) = Edit::between(&before, after) else { return };
+        let typing = self.last_edit.is_some_and(|last| last.elapsed() < TYPING_PAUSE);
+        if !typing || !self.undo.last_mut().is_some_and(|last| last.merge(&edit)) {
+            self.undo.push(edit);
+            if self.undo.len() > MAX_STEPS {
+                self.undo.remove(0);
+            }
+        }
+        self.redo.clear();
+        self.last_edit = Some(Instant::now());
+    }
+
+    // The text after undoing the last step and where the cursor goes, or None
+    // if there's nothing left to undo
+    pub fn undo(&mut self, text: &str) -> Option<(String, usize)> {
+        let edit = self.undo.pop()?;
+        let Some(undone) = edit.inverse().apply(text) else {
+            return self.forget();
+        };
+        let cursor = edit.start + edit.removed.len();
+        self.redo.push(edit);
+        self.text = Some(undone.clone());
+        self.last_edit = None;
+        Some((undone, cursor))
+    }
+
+    pub fn redo(&mut self, text: &str) -> Option<(String, usize)> {
+        let edit = self.redo.pop()?;
+        let Some(redone) = edit.apply(text) else {
+            return self.forget();
+        };
+        let cursor = edit.start + edit.inserted.len();
+        self.undo.push(edit);
+        self.text = Some(redone.clone());
+        self.last_edit = None;
+        Some((redone, cursor))
+    }
+
+    // The text changed in a way the history didn't see, its steps no longer line up
+    fn forget(&mut self) -> Option<(String, usize)> {
+        warn!("Undo history doesn't match the text anymore, dropping it");
+        self.undo.clear();
+        self.redo.clear();
+        None
+    }
+
+    fn is_empty(&self) -> bool {
+        self.undo.is_empty() && self.redo.is_empty()
+    }
+}
+
+// Only compared within one build of the editor, a different hasher after an
+// update just drops the old histories
+fn fingerprint(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Database {
+    pub fn history(&mut self, key: &str) -> &mut TextHistory {
+        self.history.entry(key.to_string()).or_default()
+    }
+
+    // Histories only come from disk into an empty workspace, a rescan keeps
+    // what's in memory
+    pub fn load_history(&mut self) {
+        if !self.keep_history || !self.history.is_empty() {
+            return;
+        }
+        let read = File::open(self.meta_dir().join(HISTORY_FILE))
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from));
+        match read {
+            Ok(history) => self.history = history,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Couldn't read the undo history: {}", e),
+        }
+    }
+
+    // Written to a temporary file first, like the search index
+    pub fn save_history(&mut self) -> io::Result<()> {
+        let path = self.meta_dir().join(HISTORY_FILE);
+        if !self.keep_history {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        self.history.retain(|_, history| !history.is_empty());
+        // A file that wasn't opened this session keeps the checksum it was loaded with
+        for history in self.history.values_mut() {
+            if let Some(text) = &history.text {
+                history.checksum = Some(fingerprint(text));
+            }
+        }
+
+        fs::create_dir_all(self.meta_dir())?;
+        let temp = self.meta_dir().join(format!("{}.tmp", HISTORY_FILE));
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        serde_json::to_writer(&mut writer, &self.history)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::test_support::workspace;
+    use crate::file_types::FileType;
+    use super::*;
+
+    fn edit(start: usize, removed: &str, inserted: &str) -> Edit {
+        Edit { start, removed: removed.to_string(), inserted: inserted.to_string() }
+    }
+
+    fn parts(edit: &Edit) -> (usize, &str, &str) {
+        (edit.start, edit.removed.as_str(), edit.inserted.as_str())
+    }
+
+    #[test]
+    fn between_trims_the_common_ends() {
+        assert!(Edit::between("same", "same").is_none());
+        assert_eq!(parts(&Edit::between("hello world", "hello there world").unwrap()), (6, "", "there "));
+        assert_eq!(parts(&Edit::between("abcabc", "abc").unwrap()), (3, "abc", ""));
+        assert_eq!(parts(&Edit::between("one two", "one 2").unwrap()), (4, "two", "2"));
+        // Never splits a character, é and è share their first byte
+        assert_eq!(parts(&Edit::between("café", "cafè").unwrap()), (3, "é", "è"));
+        assert_eq!(parts(&Edit::between("aé", "aéé").unwrap()), (3, "", "é"));
+    }
+
+    #[test]
+    fn between_and_apply_round_trip() {
+        let pairs = [("", "abc"), ("abc", ""), ("x\ny\n", "x\n\ny\n"), ("日本語", "日本"), ("ab", "ba")];
+        for (before, after) in pairs {
+            let edit = Edit::between(before, after).unwrap();
+            assert_eq!(edit.apply(before).as_deref(), Some(after));
+            assert_eq!(edit.inverse().apply(after).as_deref(), Some(before));
+        }
+        assert!(edit(0, "x", "").apply("abc").is_none());
+    }
+
+    #[test]
+    fn merge_joins_one_run_of_typing() {
+        let mut step = edit(0, "", "h");
+        assert!(step.merge(&edit(1, "", "i")));
+        assert!(step.merge(&edit(2, "", " ")));
+        assert_eq!(parts(&step), (0, "", "hi "));
+        // A new word, a new line or typing elsewhere starts a new step
+        assert!(!step.merge(&edit(3, "", "x")));
+        assert!(!edit(0, "", "a").merge(&edit(1, "", "\n")));
+        assert!(!edit(0, "", "a").merge(&edit(5, "", "b")));
+        // Typing and deleting don't mix
+        assert!(!edit(0, "", "a").merge(&edit(0, "a", "")));
+    }
+
+    #[test]
+    fn merge_joins_backspace_and_delete() {
+        let mut step = edit(5, "e", "");
+        assert!(step.merge(&edit(4, "d", "")));
+        assert_eq!(parts(&step), (4, "de", ""));
+        assert!(step.merge(&edit(4, "f", "")));
+        assert_eq!(parts(&step), (4, "def", ""));
+        assert!(!step.merge(&edit(9, "x", "")));
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = TextHistory::default();
+        history.sync("a");
+        history.record("ab");
+        history.last_edit = None;
+        history.record("ab\nc");
+        assert_eq!(history.undo("ab\nc"), Some(("ab".to_string(), 2)));
+        assert_eq!(history.undo("ab"), Some(("a".to_string(), 1)));
+        assert_eq!(history.undo("a"), None);
+        assert_eq!(history.redo("a"), Some(("ab".to_string(), 2)));
+        // Text that doesn't line up with the steps drops them
+        assert_eq!(history.redo("x"), None);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn history_survives_a_restart_unless_the_file_changed() {
+        let mut db = workspace("history-restart");
+        db.keep_history = true;
+        for key in ["kept.txt", "changed.txt"] {
+            db.insert(key.to_string(), "first".to_string());
+            db.history(key).sync("first");
+            db.insert(key.to_string(), "first\nsecond".to_string());
+            db.history(key).record("first\nsecond");
+            db.save(key).unwrap();
+        }
+        db.save_history().unwrap();
+        let dir = db.current_dir();
+        fs::write(db.disk_path("changed.txt"), "edited elsewhere").unwrap();
+
+        let mut db = Database::new(&dir, true);
+        db.keep_history = true;
+        db.load().unwrap();
+        for key in ["kept.txt", "changed.txt"] {
+            let Some(FileType::Text(file)) = db.get_file(key) else { panic!("{} wasn't loaded", key) };
+            let text = file.get_contents();
+            db.history(key).sync(&text);
+        }
+        assert_eq!(db.history("kept.txt").undo("first\nsecond"), Some(("first".to_string(), 5)));
+        assert_eq!(db.history("changed.txt").undo("edited elsewhere"), None);
+    }
+}
@@ -1,74 +1,230 @@
 use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
 use std::path::Path;
 use std::rc::Rc;
+use log::{debug, info, warn};
 use rfd::FileDialog;
 use slint::{ComponentHandle, Image, Model, SharedString, VecModel, Weak};
 use slint::private_unstable_api::re_exports::KeyEvent;
-use crate::{AppWindow, build_file_tree, CURRENT_FILE, FileTreeItemData, open_file, remove_invalid_dirs};
+use crate::{AppWindow, remove_invalid_dirs, SaveFailure};
 use crate::cache::Database;
+use crate::file_tree::FileTreeModel;
+use crate::file_types::FileType;
+use crate::highlight::Pane;
+use crate::lines::show_rows;
+use crate::logging;
+use crate::notifications::Notifier;
+use crate::sorting::SortMode;
 use crate::state::State;
+use crate::tabs::{flush_buffer, open_tab, show_active, show_tabs, update_tabs};
 
 
-pub fn handle_textbox_edit(db: Rc<RefCell<Database>>, ui_handle: Weak<AppWindow>) {
-    ui_handle.unwrap().on_edited(move |_| {
+pub fn handle_textbox_edit(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_edited(move |text: SharedString| {
         let ui = ui_handle.unwrap();
+        let Some(current) = state.borrow().current_file().map(str::to_string) else {
+            ui.invoke_new_file();
+            return;
+        };
         let mut binding = db.borrow_mut();
-        unsafe {
-            if CURRENT_FILE.is_none() {
-                ui.invoke_new_file();
-            } else {
-                binding.mark_dirty(CURRENT_FILE.clone().unwrap());
-            }
+        binding.history(&current).record(&text);
+        binding.highlights.update(Pane::Main, &text);
+        binding.layouts.update(Pane::Main, &text);
+        show_rows(&binding, &ui, Pane::Main);
+        // The side pane was given the same text if it shows this file too
+        if state.borrow().side.as_ref().is_some_and(|side| side.key == current) {
+            binding.highlights.update(Pane::Side, &text);
+            binding.layouts.update(Pane::Side, &text);
+            show_rows(&binding, &ui, Pane::Side);
+        }
+        // The tab only needs redrawing the first time its file turns dirty
+        if !binding.data.get(&current).is_some_and(|file| file.is_dirty()) {
+            binding.mark_dirty(current);
+            show_tabs(&binding, &state.borrow(), &ui);
         }
-        ();
     });
 }
 
-pub fn handle_click_file_tree(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<VecModel<FileTreeItemData>>, ui_handle: Weak<AppWindow>) {
+// Ctrl+Z and Ctrl+Y in either pane. They go through the file's own history,
+// the text box's is lost every time another file is put in it.
+pub fn handle_text_history(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    let (weak, db_clone, state_clone) = (ui_handle.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui_handle.unwrap().on_undo_edit(move |side: bool| {
+        step_history(&db_clone, &state_clone, &weak.unwrap(), side, true);
+    });
+    let weak = ui_handle.clone();
+    ui_handle.unwrap().on_redo_edit(move |side: bool| {
+        step_history(&db, &state, &weak.unwrap(), side, false);
+    });
+}
+
+fn step_history(db: &Rc<RefCell<Database>>, state: &Rc<RefCell<State>>, ui: &AppWindow, side: bool, undo: bool) {
+    let key = if side {
+        state.borrow().side.as_ref().map(|tab| tab.key.clone())
+    } else {
+        state.borrow().current_file().map(str::to_string)
+    };
+    let Some(key) = key else { return };
+    let text = if side { ui.invoke_get_side_box() } else { ui.invoke_get_current_box() };
+    let stepped = {
+        let mut binding = db.borrow_mut();
+        let history = binding.history(&key);
+        if undo { history.undo(&text) } else { history.redo(&text) }
+    };
+    // Putting the text back goes through the edited callbacks like typing
+    // does, so the Database can't be borrowed here anymore
+    let Some((text, cursor)) = stepped else { return };
+    if side {
+        ui.invoke_set_side_box(SharedString::from(text), cursor as i32);
+    } else {
+        ui.invoke_set_current_box(SharedString::from(text));
+        ui.invoke_highlight(cursor as i32, cursor as i32);
+    }
+}
+
+pub fn handle_click_file_tree(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_clicked(move |index: i32| {
         let mut binding = db.borrow_mut();
         let opt = model.row_data(index.try_into().unwrap());
         if opt.is_some() {
 
             let item = opt.unwrap();
-            println!("Clicked on {}", item.full_path);
-            if binding.contains(item.full_path.as_str()) {
-                open_file(&mut binding, &mut state.borrow_mut(), ui_handle.clone(), Some(item.full_path.to_string()));
-            } else {
+            debug!("Clicked on {}", item.full_path);
+            binding.selection = vec![item.full_path.to_string()];
+            binding.selection_anchor = Some(item.full_path.to_string());
+            model.update_selection(&binding);
+            ui_handle.unwrap().set_selection_count(1);
+            if let Some(FileType::Inaccessible(entry)) = binding.data.get(item.full_path.as_str()) {
+                // Says in the log why it can't be opened
+                entry.display();
+            } else if binding.contains(item.full_path.as_str()) {
+                open_tab(&mut binding, &mut state.borrow_mut(), &ui_handle.unwrap(), item.full_path.as_str());
+            } else if !binding.is_filtering() {
                 binding.toggle_collapse(item.full_path.as_str());
-                println!("Collapsed {}", item.full_path);
-                drop(binding);
-                build_file_tree(db.clone(), model.clone());
+                debug!("Collapsed {}", item.full_path);
+                model.toggle(&binding, item.full_path.as_str());
             }
         }
     });
 }
 
-pub fn handle_new_file_button(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<VecModel<FileTreeItemData>>, ui_handle: Weak<AppWindow>) {
+pub fn handle_new_file_button(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_new_file(move || {
         let mut binding = db.borrow_mut();
         let name = binding.get_unique_key("new file");
         binding.insert(name.to_owned(), "".to_owned());
-        open_file(&mut binding, &mut state.borrow_mut(), ui_handle.clone(), Some(name.to_owned()));
-        binding.save(&name);
-        drop(binding);
-        build_file_tree(db.clone(), model.clone());
-
+        model.insert_key(&binding, &name);
+        open_tab(&mut binding, &mut state.borrow_mut(), &ui_handle.unwrap(), &name);
+        if let Err(e) = binding.save(&name) {
+            notifier.error(format!("Couldn't create {}", name), e);
+        }
+        model.refresh_key(&binding, &name);
     });
 }
 
-pub fn handle_close(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+pub fn handle_close(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_close(move || {
         let ui = ui_handle.unwrap();
-        let mut binding = db.borrow_mut();
+        info!("Window close requested");
+        if save_before_exit(&mut db.borrow_mut(), &mut state.borrow_mut(), &notifier, &ui) {
+            ui.window().hide().expect("Failed to hide window");
+        }
+    });
+}
+
+// Saves everything before the window goes away. Files that can't be written
+// are listed in the save error popup instead and the window stays open, as
+// it does when the settings can't be written.
+pub fn save_before_exit(db: &mut Database, state: &mut State, notifier: &Notifier, ui: &AppWindow) -> bool {
+    flush_buffer(db, state, ui);
+    let state_saved = match state.save() {
+        Ok(()) => true,
+        Err(e) => {
+            let ui_handle = ui.as_weak();
+            notifier.error_with_action("Couldn't save settings", e, "Close anyway", move || {
+                ui_handle.unwrap().window().hide().expect("Failed to hide window");
+            });
+            false
+        }
+    };
+    let saved = db.save_all();
+    // The index is only a cache, losing it just means rebuilding it next time
+    if let Err(e) = db.save_index() {
+        warn!("Couldn't save the search index: {}", e);
+    }
+    if let Err(e) = db.save_history() {
+        warn!("Couldn't save the undo history: {}", e);
+    }
+    match saved {
+        Ok(()) => state_saved,
+        Err(failures) => {
+            let failures = failures.iter().map(|f| SaveFailure {
+                key: SharedString::from(f.key.clone()),
+                error: SharedString::from(f.error.to_string())
+            }).collect::<Vec<_>>();
+            ui.set_save_failures(Rc::new(VecModel::from(failures)).into());
+            ui.invoke_show_save_errors();
+            false
+        }
+    }
+}
+
+pub fn handle_save_failures(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, notifier: Rc<Notifier>, weak: Weak<AppWindow>) {
+    let ui_handle = weak.clone();
+    let binding = Rc::clone(&db);
+    let state_clone = Rc::clone(&state);
+    let notifier_clone = Rc::clone(&notifier);
+    ui_handle.unwrap().on_save_as(move |key: SharedString| {
+        let ui = ui_handle.unwrap();
+        let name = key.rsplit('/').next().unwrap_or("").to_string();
+        if let Some(target) = FileDialog::new().set_file_name(name).save_file() {
+            let result = binding.borrow_mut().save_as(key.as_str(), &target);
+            match result {
+                Ok(()) => {
+                    notifier_clone.info(format!("Saved {} to {}", key, target.display()));
+                    resolve_save_failure(&mut binding.borrow_mut(), &mut state_clone.borrow_mut(), &notifier_clone, &ui, key.as_str());
+                }
+                Err(e) => notifier_clone.error(format!("Couldn't save {} as {}", key, target.display()), e),
+            }
+        }
+    });
+
+    let ui_handle = weak.clone();
+    let binding = Rc::clone(&db);
+    let state_clone = Rc::clone(&state);
+    ui_handle.unwrap().on_retry_elevated(move |key: SharedString| {
+        let ui = ui_handle.unwrap();
+        let result = binding.borrow_mut().save_elevated(key.as_str());
+        match result {
+            Ok(()) => {
+                notifier.info(format!("Saved {}", key));
+                resolve_save_failure(&mut binding.borrow_mut(), &mut state_clone.borrow_mut(), &notifier, &ui, key.as_str());
+            }
+            Err(e) => notifier.error(format!("Elevated save of {} failed", key), e),
+        }
+    });
+
+    let ui_handle = weak.clone();
+    ui_handle.unwrap().on_discard_and_close(move || {
+        let ui = ui_handle.unwrap();
+        ui.invoke_hide_popups();
         ui.window().hide().expect("Failed to hide window");
-        println!("Window close requested");
-        open_file(&mut binding, &mut state.borrow_mut(), ui_handle.clone(), None);
-        binding.save_all().expect("Failed to save all files on exit!");
-        state.borrow().save().expect("Failed to save state");
     });
 }
 
+fn resolve_save_failure(db: &mut Database, state: &mut State, notifier: &Notifier, ui: &AppWindow, key: &str) {
+    let remaining = ui.get_save_failures().iter().filter(|f| f.key.as_str() != key).collect::<Vec<_>>();
+    if remaining.is_empty() {
+        ui.invoke_hide_popups();
+        if save_before_exit(db, state, notifier, ui) {
+            ui.window().hide().expect("Failed to hide window");
+        }
+    } else {
+        ui.set_save_failures(Rc::new(VecModel::from(remaining)).into());
+    }
+}
+
 pub fn handle_close_popups(ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_close_popups(move || {
         let ui = ui_handle.unwrap();
@@ -76,14 +232,20 @@ pub fn handle_close_popups(ui_handle: Weak<AppWindow>) {
     });
 }
 
-pub fn handle_change_background_image(state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+pub fn handle_log_panel(ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_refresh_log(move || {
+        ui_handle.unwrap().set_log_text(SharedString::from(logging::recent_lines()));
+    });
+}
+
+pub fn handle_change_background_image(state: Rc<RefCell<State>>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_open_background_image_selection_dialog(move || {
         let ui = ui_handle.unwrap();
         if let Some(path) = FileDialog::new().add_filter("Images",&vec!["png", "jpg"]).pick_file() {
             let p = path.display().to_string().clone();
-            let path = Path::new(p.as_str());
-
-            ui.invoke_set_background_image(Image::load_from_path(path).unwrap());
+            if !load_background_image(&ui, &notifier, p.as_str()) {
+                return SharedString::from("None");
+            }
             ui.set_current_background(SharedString::from(p.clone()));
             state.borrow_mut().background_image_path = Some(p.clone());
             return SharedString::from(p);
@@ -94,89 +256,368 @@ pub fn handle_change_background_image(state: Rc<RefCell<State>>, ui_handle: Weak
     });
 }
 
-pub fn handle_change_dir(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<VecModel<FileTreeItemData>>, ui_handle: Weak<AppWindow>) {
+// Shows the image behind the editor. An image that can't be loaded leaves the
+// current background alone and offers to pick another one.
+pub fn load_background_image(ui: &AppWindow, notifier: &Notifier, path: &str) -> bool {
+    match Image::load_from_path(Path::new(path)) {
+        Ok(image) => {
+            ui.invoke_set_background_image(image);
+            true
+        }
+        Err(e) => {
+            let ui_handle = ui.as_weak();
+            notifier.error_with_action(format!("Couldn't load background image {}", path), e, "Choose another", move || {
+                ui_handle.unwrap().invoke_open_background_image_selection_dialog();
+            });
+            false
+        }
+    }
+}
+
+// Scans the workspace into the tree. When the folder can't be read the tree
+// is left with whatever was found and the error offers to scan again.
+pub fn load_workspace(db: &Rc<RefCell<Database>>, model: &Rc<FileTreeModel>, notifier: &Rc<Notifier>) {
+    let result = db.borrow_mut().load();
+    model.rebuild(&db.borrow());
+    if let Err(e) = result {
+        let dir = db.borrow().current_dir();
+        let (db, model, notifier_clone) = (Rc::clone(db), model.clone(), Rc::clone(notifier));
+        notifier.error_with_action(format!("Couldn't open {}", dir), e, "Retry", move || {
+            // Nothing to retry once another folder has been opened
+            if db.borrow().current_dir() == dir {
+                db.borrow_mut().change_dirs(dir.clone());
+                load_workspace(&db, &model, &notifier_clone);
+            }
+        });
+    }
+}
+
+pub fn handle_change_dir(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_open_working_directory_selection_dialog(move || {
         let ui = ui_handle.unwrap();
         let mut binding = db.borrow_mut();
         if let Some(path) = FileDialog::new().pick_folder() {
             let p = path.display().to_string().clone();
-            println!("Changing directories!");
-            open_file(&mut binding, &mut state.borrow_mut(), ui.as_weak(), None);
+            info!("Changing directory to {}", p);
+            flush_buffer(&mut binding, &mut state.borrow_mut(), &ui);
             binding.change_dirs(p.clone());
-            binding.load().expect("Failed to load db");
-            println!("Loaded new directory!");
+            if let Err(e) = logging::attach_file(&binding.meta_dir()) {
+                notifier.error("Couldn't open the log file", e);
+            }
             drop(binding);
-            build_file_tree(Rc::clone(&db), model.clone());
+            load_workspace(&db, &model, &notifier);
+            info!("Loaded {}", p);
             ui.invoke_hide_popups();
             ui.set_current_dir(SharedString::from(p.clone()));
-            state.borrow_mut().data_dir = p.clone();
+            let mut state = state.borrow_mut();
+            state.data_dir = p.clone();
+            // The tabs belong to the old folder
+            state.tabs.clear();
+            state.active_tab = None;
+            show_active(&mut db.borrow_mut(), &mut state, &ui);
 
             return SharedString::from(p.clone());
         } else {
-            println!("aaaasdfadf\n");
+            debug!("No directory picked");
             return SharedString::from("None");
         }
 
     });
 }
 
+pub fn handle_follow_symlinks(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_toggle_follow_symlinks(move |follow: bool| {
+        let ui = ui_handle.unwrap();
+        let mut binding = db.borrow_mut();
+        flush_buffer(&mut binding, &mut state.borrow_mut(), &ui);
+        if let Err(failures) = binding.save_all() {
+            let keys = failures.iter().map(|f| f.key.as_str()).collect::<Vec<_>>();
+            notifier.error("Some files couldn't be saved before rescanning", keys.join(", "));
+        }
+        binding.set_follow_symlinks(follow);
+        let dir = binding.current_dir();
+        binding.change_dirs(dir);
+        drop(binding);
+        load_workspace(&db, &model, &notifier);
+        ui.set_follow_symlinks(follow);
+        let mut state = state.borrow_mut();
+        state.follow_symlinks = follow;
+        update_tabs(&mut db.borrow_mut(), &mut state, &ui, &[]);
+    });
+}
+
+pub fn handle_sort_mode(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_change_sort(move |mode: SharedString, folders_first: bool| {
+        let ui = ui_handle.unwrap();
+        let mode = SortMode::from_name(mode.as_str());
+        let mut binding = db.borrow_mut();
+        binding.sort_mode = mode;
+        binding.folders_first = folders_first;
+        model.rebuild(&binding);
+        drop(binding);
+        ui.set_sort_mode(SharedString::from(mode.name()));
+        ui.set_folders_first(folders_first);
+        state.borrow_mut().sort_mode = mode;
+        state.borrow_mut().folders_first = folders_first;
+    });
+}
+
+pub fn handle_search_result_limit(state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_change_result_limit(move |limit: i32| {
+        let limit = limit.max(1);
+        ui_handle.unwrap().set_search_result_limit(limit);
+        state.borrow_mut().search_result_limit = limit as usize;
+    });
+}
+
+pub fn handle_keep_history(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_toggle_keep_history(move |keep: bool| {
+        ui_handle.unwrap().set_keep_history(keep);
+        db.borrow_mut().keep_history = keep;
+        state.borrow_mut().keep_history = keep;
+    });
+}
+
+pub fn handle_relative_numbers(state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_toggle_relative_numbers(move |relative: bool| {
+        ui_handle.unwrap().set_relative_numbers(relative);
+        state.borrow_mut().relative_numbers = relative;
+    });
+}
+
+pub fn handle_filter(db: Rc<RefCell<Database>>, model: Rc<FileTreeModel>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_filter_changed(move |filter: SharedString, fuzzy: bool| {
+        let mut binding = db.borrow_mut();
+        binding.set_filter(filter.as_str(), fuzzy);
+        model.rebuild(&binding);
+    });
+}
+
 pub fn handle_shortcuts(ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_process_shortcut(move |event: KeyEvent| {
         let ui = ui_handle.unwrap();
         if event.modifiers.control && event.text.eq_ignore_ascii_case("t") {
             ui.invoke_show_search_popup();
+        } else if event.modifiers.control && event.text.eq_ignore_ascii_case("p") {
+            ui.invoke_show_quick_open();
+        } else if event.modifiers.control && event.text.eq_ignore_ascii_case("f") {
+            ui.invoke_show_find_bar();
+        } else if event.modifiers.control && event.text.eq_ignore_ascii_case("g") {
+            ui.invoke_show_go_to_line();
+        } else if event.modifiers.control && event.text.eq_ignore_ascii_case("z") && !event.modifiers.shift {
+            // Only reaches here when no text box took it, so it's for the tree
+            ui.invoke_undo_file_operation();
+        } else if event.modifiers.control && (event.text.eq_ignore_ascii_case("y") || event.text.eq_ignore_ascii_case("z")) {
+            ui.invoke_redo_file_operation();
+        } else if event.modifiers.control && event.text == "\\" {
+            ui.invoke_split_editor(SharedString::from(if ui.get_split() == "None" { "Right" } else { "None" }));
         }
     });
 }
 
-pub fn handle_delete(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, mut model: Rc<VecModel<FileTreeItemData>>, ui_handle: Weak<AppWindow>) {
+pub fn handle_delete(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
     ui_handle.unwrap().on_delete_file(move |to_delete: SharedString| {
+        run_file_operation(&db, &state, &model, &notifier, &ui_handle.unwrap(), |db| {
+            db.delete_keys(&[to_delete.to_string()]).map(|_| Vec::new())
+        });
+    });
+}
+
+pub fn handle_select(db: Rc<RefCell<Database>>, model: Rc<FileTreeModel>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_select(move |index: i32, ctrl: bool, shift: bool| {
+        let ui = ui_handle.unwrap();
         let mut binding = db.borrow_mut();
-        let model = &mut model;
-        unsafe {
-            if CURRENT_FILE.is_some() {
-                let current = CURRENT_FILE.clone().unwrap().to_string();
-                if current.as_str() == to_delete.as_str() {
-                    open_file(&mut binding, &mut state.borrow_mut(), ui_handle.clone(), None);
-                }
-            }
-            let mut i = 0;
-            for  value in model.iter() {
-                if value.name.as_str() == to_delete.as_str() {
-                    binding.delete_file(value.name.as_str());
-                    binding.remove(value.name.as_str());
-                    model.remove(i);
+        let Some(item) = model.row_data(index as usize) else { return };
+        let path = item.full_path.to_string();
 
+        let anchor_row = binding.selection_anchor.as_ref()
+            .and_then(|anchor| model.iter().position(|row| row.full_path.as_str() == anchor));
+        if let (true, Some(anchor_row)) = (shift, anchor_row) {
+            let (from, to) = (anchor_row.min(index as usize), anchor_row.max(index as usize));
+            let range = (from..=to).filter_map(|x| model.row_data(x)).map(|row| row.full_path.to_string());
+            if !ctrl {
+                binding.selection.clear();
+            }
+            for p in range {
+                if !binding.selection.contains(&p) {
+                    binding.selection.push(p);
                 }
-                i += 1;
             }
-        }();
+        } else if ctrl {
+            if binding.selection.contains(&path) {
+                binding.selection.retain(|s| *s != path);
+            } else {
+                binding.selection.push(path.clone());
+            }
+            binding.selection_anchor = Some(path);
+        } else {
+            binding.selection = vec![path.clone()];
+            binding.selection_anchor = Some(path);
+        }
+
+        model.update_selection(&binding);
+        ui.set_selection_count(binding.selection.len() as i32);
     });
 }
 
+pub fn handle_bulk_actions(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, weak: Weak<AppWindow>) {
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_delete_selection(move || {
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| {
+            let selection = db.selection.clone();
+            db.delete_keys(&selection).map(|_| Vec::new())
+        });
+    });
 
-pub fn handle_rename(db: Rc<RefCell<Database>>, mut model: Rc<VecModel<FileTreeItemData>>, ui_handle: Weak<AppWindow>) {
-    ui_handle.unwrap().on_rename_file(move |new_name: SharedString| {
-        let new_name = SharedString::from(remove_invalid_dirs(new_name.to_string()));
-        let mut binding = db.borrow_mut();
-        let model = &mut model;
-        unsafe {
-            let current = CURRENT_FILE.clone().unwrap().to_string();
-            binding.rename(current.as_str(), new_name.as_str());
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_move_selection(move |folder: SharedString| {
+        let folder = remove_invalid_dirs(folder.to_string());
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| {
+            let selection = db.selection.clone();
+            db.move_keys(&selection, &folder)
+        });
+    });
+
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_copy_selection(move |folder: SharedString| {
+        let folder = remove_invalid_dirs(folder.to_string());
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| {
+            let selection = db.selection.clone();
+            db.copy_keys(&selection, &folder).map(|_| Vec::new())
+        });
+    });
 
-            for value in model.iter() {
-                if value.full_path.as_str() == current.as_str() {
-                    binding.delete_file(current.as_str());
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_export_selection(move || {
+        let Some(dir) = FileDialog::new().pick_folder() else { return };
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| {
+            let selection = db.selection.clone();
+            db.export_keys(&selection, &dir).map(|_| Vec::new())
+        });
+    });
 
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_duplicate_selection(move || {
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| {
+            let selection = db.selection.clone();
+            db.duplicate_keys(&selection).map(|_| Vec::new())
+        });
+    });
 
-                    println!("renamed to {}", value.name.as_str());
+    let (ui_handle, db_clone) = (weak.clone(), Rc::clone(&db));
+    ui_handle.unwrap().on_copy_to_clipboard(move |cut: bool| {
+        let mut binding = db_clone.borrow_mut();
+        binding.clipboard = binding.selection.clone();
+        binding.clipboard_cut = cut;
+        ui_handle.unwrap().set_can_paste(!binding.clipboard.is_empty());
+    });
 
-                }
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_paste_clipboard(move || {
+        let ui = ui_handle.unwrap();
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui, |db| {
+            let folder = paste_target(db);
+            let clipboard = db.clipboard.clone();
+            if db.clipboard_cut {
+                db.clipboard.clear();
+                db.move_keys(&clipboard, &folder)
+            } else {
+                db.copy_keys(&clipboard, &folder).map(|_| Vec::new())
             }
-            drop(binding);
-            build_file_tree(db.clone(), model.clone());
-            CURRENT_FILE = Some(new_name.as_str().to_owned());
-        }();
-        return new_name.clone();
+        });
+        ui.set_can_paste(!db_clone.borrow().clipboard.is_empty());
+    });
+
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_undo_file_operation(move || {
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| db.undo_operation());
+    });
+
+    let (ui_handle, db_clone, state_clone, model_clone, notifier_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier));
+    ui_handle.unwrap().on_redo_file_operation(move || {
+        run_file_operation(&db_clone, &state_clone, &model_clone, &notifier_clone, &ui_handle.unwrap(), |db| db.redo_operation());
+    });
+}
+
+// Runs a file operation with the open buffer flushed first, then updates the
+// tabs (following files that were moved) and the tree. Returns whether
+// the operation succeeded.
+pub(crate) fn run_file_operation(db: &Rc<RefCell<Database>>, state: &Rc<RefCell<State>>, model: &Rc<FileTreeModel>, notifier: &Notifier, ui: &AppWindow, operation: impl FnOnce(&mut Database) -> io::Result<Vec<(String, String)>>) -> bool {
+    let mut binding = db.borrow_mut();
+    flush_buffer(&mut binding, &mut state.borrow_mut(), ui);
+
+    let before = binding.data.keys().cloned().collect::<HashSet<_>>();
+    let result = operation(&mut binding);
+    let succeeded = result.is_ok();
+    let renames = match result {
+        Ok(renames) => renames,
+        Err(e) => {
+            notifier.error("File operation failed", e);
+            Vec::new()
+        }
+    };
+    binding.selection.clear();
+    binding.selection_anchor = None;
+    ui.set_selection_count(0);
+    ui.set_undo_label(SharedString::from(binding.operations.undo_label().unwrap_or_default()));
+    ui.set_redo_label(SharedString::from(binding.operations.redo_label().unwrap_or_default()));
+
+    // Only touch the rows that actually changed
+    let after = binding.data.keys().cloned().collect::<HashSet<_>>();
+    for removed in before.difference(&after) {
+        model.remove_key(&binding, removed);
+    }
+    for added in after.difference(&before) {
+        model.insert_key(&binding, added);
+    }
+    model.update_selection(&binding);
+    drop(binding);
+
+    update_tabs(&mut db.borrow_mut(), &mut state.borrow_mut(), ui, &renames);
+    succeeded
+}
+
+// Pasting goes into the selected folder, or next to the selected file
+fn paste_target(db: &Database) -> String {
+    match db.selection.first() {
+        Some(path) if db.contains(path) => path.rfind('/').map(|i| path[..i].to_string()).unwrap_or_default(),
+        Some(path) => path.clone(),
+        None => String::new(),
+    }
+}
+
+pub fn handle_rename(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_rename_file(move |new_name: SharedString| {
+        let new_name = SharedString::from(remove_invalid_dirs(new_name.to_string()));
+        let mut binding = db.borrow_mut();
+        let mut state = state.borrow_mut();
+        let Some(current) = state.current_file().map(str::to_string) else { return new_name };
+        if new_name.is_empty() {
+            return SharedString::from(current);
+        }
+        if current == new_name.as_str() {
+            return new_name;
+        }
+        if let Err(e) = binding.rename_key(current.as_str(), new_name.as_str()) {
+            notifier.error(format!("Couldn't rename {}", current), e);
+            return SharedString::from(current);
+        }
+        info!("Renamed {} to {}", current, new_name.as_str());
+        model.remove_key(&binding, current.as_str());
+        model.insert_key(&binding, new_name.as_str());
+        model.set_open(Some(new_name.as_str()));
+        // A new extension may mean a different language
+        let ui = ui_handle.unwrap();
+        ui.set_editor_highlighted(binding.highlights.open(Pane::Main, new_name.as_str(), ui.invoke_get_current_box().as_str()));
+        show_rows(&binding, &ui, Pane::Main);
+        for tab in state.tabs.iter_mut().filter(|tab| tab.key == current) {
+            tab.key = new_name.to_string();
+        }
+        if let Some(side) = state.side.as_mut().filter(|side| side.key == current) {
+            side.key = new_name.to_string();
+            ui.set_side_key(new_name.clone());
+        }
+        show_tabs(&binding, &state, &ui);
+        new_name
     });
 }
@@ -0,0 +1,540 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
+use regex_syntax::hir::{Hir, HirKind};
+use crate::cache::Database;
+use crate::file_types::{FileInfo, FileType};
+
+const INDEX_FILE: &str = "search.index";
+// Bumped whenever the layout of the index file changes, old files are rebuilt
+const MAGIC: &[u8; 4] = b"TRI1";
+
+// Three bytes of ASCII-lowercased text packed into an int
+type Trigram = u32;
+
+struct Entry {
+    size: u64,
+    modified: Option<SystemTime>,
+    trigrams: Vec<Trigram>,
+    // Entries read back from disk could be out of date until a refresh has
+    // compared them with the file, so they're searched regardless
+    checked: bool
+}
+
+// Maps every trigram to the files containing it, so a search only has to
+// read the files that contain all the trigrams of the query. Files the index
+// doesn't know about are always searched, so it can lag behind without
+// hiding results.
+#[derive(Default)]
+pub struct SearchIndex {
+    entries: HashMap<String, Entry>,
+    postings: HashMap<Trigram, HashSet<String>>,
+    changed: bool
+}
+
+impl SearchIndex {
+    pub fn update(&mut self, key: &str, text: &[u8], info: FileInfo) {
+        self.insert(key, trigrams(text), info);
+    }
+
+    fn insert(&mut self, key: &str, trigrams: Vec<Trigram>, info: FileInfo) {
+        self.remove(key);
+        for trigram in &trigrams {
+            self.postings.entry(*trigram).or_default().insert(key.to_string());
+        }
+        self.entries.insert(key.to_string(), Entry { size: info.size, modified: info.modified, trigrams, checked: true });
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let Some(entry) = self.entries.remove(key) else { return };
+        for trigram in entry.trigrams {
+            if let Some(keys) = self.postings.get_mut(&trigram) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&trigram);
+                }
+            }
+        }
+        self.changed = true;
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) {
+        let Some(entry) = self.entries.remove(old) else { return };
+        for trigram in &entry.trigrams {
+            if let Some(keys) = self.postings.get_mut(trigram) {
+                keys.remove(old);
+                keys.insert(new.to_string());
+            }
+        }
+        self.entries.insert(new.to_string(), entry);
+        self.changed = true;
+    }
+
+    fn stamp(&self, key: &str) -> Option<Stamp> {
+        self.entries.get(key).map(|e| (e.size, e.modified))
+    }
+
+    // Keys that may contain every trigram, or None when the query has none
+    // to narrow by
+    fn candidates(&self, required: &[Trigram]) -> Option<HashSet<&str>> {
+        if required.is_empty() {
+            return None;
+        }
+        let mut lists = Vec::new();
+        for trigram in required {
+            match self.postings.get(trigram) {
+                Some(keys) => lists.push(keys),
+                // No indexed file has it at all
+                None => return Some(HashSet::new()),
+            }
+        }
+        // Intersecting from the rarest trigram up keeps the working set small
+        lists.sort_by_key(|keys| keys.len());
+        let mut result = lists[0].iter().map(|k| k.as_str()).collect::<HashSet<_>>();
+        for keys in &lists[1..] {
+            result.retain(|k| keys.contains(*k));
+        }
+        Some(result)
+    }
+
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(dir.join(INDEX_FILE))?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unknown index format"));
+        }
+        let mut index = SearchIndex::default();
+        let count = read_u32(&mut reader)?;
+        for _ in 0..count {
+            let mut key = vec![0u8; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut key)?;
+            let key = String::from_utf8(key).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let size = read_u64(&mut reader)?;
+            let secs = read_u64(&mut reader)?;
+            let nanos = read_u32(&mut reader)?;
+            let modified = if secs == u64::MAX { None } else { Some(UNIX_EPOCH + Duration::new(secs, nanos)) };
+            let trigrams = (0..read_u32(&mut reader)?).map(|_| read_u32(&mut reader)).collect::<io::Result<Vec<_>>>()?;
+            for trigram in &trigrams {
+                index.postings.entry(*trigram).or_default().insert(key.clone());
+            }
+            index.entries.insert(key, Entry { size, modified, trigrams, checked: false });
+        }
+        Ok(index)
+    }
+
+    // Written to a temporary file first so a crash never leaves half an index
+    pub fn write(&mut self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let temp = dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        for (key, entry) in &self.entries {
+            writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            writer.write_all(key.as_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            let since_epoch = entry.modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+            writer.write_all(&since_epoch.map(|d| d.as_secs()).unwrap_or(u64::MAX).to_le_bytes())?;
+            writer.write_all(&since_epoch.map(|d| d.subsec_nanos()).unwrap_or(0).to_le_bytes())?;
+            writer.write_all(&(entry.trigrams.len() as u32).to_le_bytes())?;
+            for trigram in &entry.trigrams {
+                writer.write_all(&trigram.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp, dir.join(INDEX_FILE))?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+impl Database {
+    // Loads the index kept for this folder. It's brought up to date with
+    // what's on disk by the background refresh, a missing or unreadable index
+    // is rebuilt from scratch there.
+    pub fn load_index(&mut self) {
+        self.index = match SearchIndex::read(&self.meta_dir()) {
+            Ok(index) => index,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Rebuilding the search index: {}", e);
+                }
+                SearchIndex::default()
+            }
+        };
+    }
+
+    // Everything a refresh needs to know, gathered without touching the disk
+    // so it can run on another thread
+    pub fn index_scan(&self) -> IndexScan {
+        let files = self.data.iter()
+            .filter_map(|(key, file)| match file {
+                FileType::Text(text_file) => Some(Pending { key: key.clone(), path: text_file.path.clone(), stamp: self.index.stamp(key) }),
+                _ => None,
+            })
+            .collect();
+        IndexScan { files }
+    }
+
+    // Folds the result of a refresh into the index. Files saved, renamed or
+    // removed while it ran are left alone since the index already has them.
+    // Returns how many files had changed.
+    pub fn apply_index_scan(&mut self, scanned: Vec<Scanned>) -> usize {
+        let mut indexed = 0;
+        for file in scanned {
+            let unchanged_path = matches!(self.data.get(&file.key), Some(FileType::Text(text_file)) if text_file.path == file.path);
+            if !unchanged_path || self.index.stamp(&file.key) != file.stamp {
+                continue;
+            }
+            match file.trigrams {
+                Some(trigrams) => {
+                    self.index.insert(&file.key, trigrams, file.info);
+                    indexed += 1;
+                }
+                None => if let Some(entry) = self.index.entries.get_mut(&file.key) {
+                    entry.checked = true;
+                },
+            }
+        }
+        let removed = self.index.entries.keys()
+            .filter(|key| !matches!(self.data.get(*key), Some(FileType::Text(_))))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in removed {
+            self.index.remove(&key);
+        }
+        if indexed > 0 {
+            info!("Indexed {} files", indexed);
+        }
+        indexed
+    }
+
+    pub fn save_index(&mut self) -> io::Result<()> {
+        if !self.index.changed {
+            return Ok(());
+        }
+        let dir = self.meta_dir();
+        self.index.write(&dir)
+    }
+
+    // Files that could match the search. None means every file has to be
+    // read, either because nothing could be learned from the query or because
+    // the pattern didn't parse. Files with unsaved edits are always included
+    // since the index only knows what's on disk.
+    pub fn search_candidates(&self, terms: &str, regex: bool, match_case: bool) -> Option<HashSet<String>> {
+        let literals = if regex { required_literals(terms)? } else { vec![terms.as_bytes().to_vec()] };
+        let required = literals.iter()
+            .flat_map(|literal| query_trigrams(literal, match_case))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let candidates = self.index.candidates(&required)?;
+
+        let mut result = candidates.into_iter().map(str::to_string).collect::<HashSet<_>>();
+        for (key, file) in &self.data {
+            let unchecked = self.index.entries.get(key).is_none_or(|entry| !entry.checked);
+            let dirty = matches!(file, FileType::Text(text_file) if text_file.dirty);
+            if matches!(file, FileType::Text(_)) && (unchecked || dirty) {
+                result.insert(key.clone());
+            }
+        }
+        Some(result)
+    }
+}
+
+// Size and modification time of a file when it was indexed
+type Stamp = (u64, Option<SystemTime>);
+
+struct Pending {
+    key: String,
+    path: PathBuf,
+    stamp: Option<Stamp>
+}
+
+pub struct IndexScan {
+    files: Vec<Pending>
+}
+
+pub struct Scanned {
+    key: String,
+    path: PathBuf,
+    // What the index had when the scan started
+    stamp: Option<Stamp>,
+    info: FileInfo,
+    // None when the file hasn't changed since it was indexed
+    trigrams: Option<Vec<Trigram>>
+}
+
+impl IndexScan {
+    // Stats every file and reads the ones that changed since they were
+    // indexed, which is how changes made outside the editor get picked up
+    pub fn run(self) -> Vec<Scanned> {
+        self.files.into_iter()
+            .filter_map(|file| {
+                let info = FileInfo::read(&file.path);
+                let trigrams = if file.stamp == Some((info.size, info.modified)) {
+                    None
+                } else {
+                    match fs::read(&file.path) {
+                        Ok(bytes) => Some(trigrams(&bytes)),
+                        // Logged quietly since this comes back on every refresh
+                        Err(e) => {
+                            debug!("Couldn't index {}: {}", file.key, e);
+                            return None;
+                        }
+                    }
+                };
+                Some(Scanned { key: file.key, path: file.path, stamp: file.stamp, info, trigrams })
+            })
+            .collect()
+    }
+}
+
+fn trigrams(text: &[u8]) -> Vec<Trigram> {
+    let mut set = text.windows(3).map(pack).collect::<Vec<_>>();
+    set.sort_unstable();
+    set.dedup();
+    set
+}
+
+fn pack(bytes: &[u8]) -> Trigram {
+    u32::from_le_bytes([bytes[0].to_ascii_lowercase(), bytes[1].to_ascii_lowercase(), bytes[2].to_ascii_lowercase(), 0])
+}
+
+// The index folds ASCII case only. When case is ignored a non-ASCII byte
+// could match some other spelling of the letter, and so can k and s (the
+// Kelvin sign and long s fold to them), so trigrams with those are skipped.
+fn query_trigrams(literal: &[u8], match_case: bool) -> Vec<Trigram> {
+    literal.windows(3)
+        .filter(|w| match_case || w.iter().all(|b| b.is_ascii() && !matches!(b.to_ascii_lowercase(), b'k' | b's')))
+        .map(pack)
+        .collect()
+}
+
+// Runs of literal text every match of the regex has to contain. Only the top
+// level sequence is looked at, anything optional or alternative just ends the
+// current run, which keeps this conservative.
+fn required_literals(pattern: &str) -> Option<Vec<Vec<u8>>> {
+    let hir = regex_syntax::Parser::new().parse(pattern).ok()?;
+    let mut runs = vec![Vec::new()];
+    collect_literals(&hir, &mut runs);
+    Some(runs.into_iter().filter(|run| run.len() >= 3).collect())
+}
+
+fn collect_literals(hir: &Hir, runs: &mut Vec<Vec<u8>>) {
+    match hir.kind() {
+        HirKind::Literal(literal) => runs.last_mut().unwrap().extend_from_slice(&literal.0),
+        HirKind::Concat(parts) => {
+            for part in parts {
+                collect_literals(part, runs);
+            }
+        }
+        HirKind::Capture(capture) => collect_literals(&capture.sub, runs),
+        // Assertions like ^ or \b don't consume text, so the run goes on
+        HirKind::Look(_) | HirKind::Empty => {}
+        HirKind::Repetition(repetition) if repetition.min > 0 => {
+            runs.push(Vec::new());
+            collect_literals(&repetition.sub, runs);
+            runs.push(Vec::new());
+        }
+        _ => runs.push(Vec::new()),
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use regex::{Regex, RegexBuilder};
+    use crate::cache::test_support::workspace;
+    use crate::file_types::TextFile;
+    use super::*;
+
+    fn literal(text: &str) -> Vec<u8> {
+        text.as_bytes().to_vec()
+    }
+
+    fn sorted(mut trigrams: Vec<Trigram>) -> Vec<Trigram> {
+        trigrams.sort_unstable();
+        trigrams
+    }
+
+    // Runs a refresh start to finish on the calling thread
+    fn refresh(db: &mut Database) {
+        let scanned = db.index_scan().run();
+        db.apply_index_scan(scanned);
+    }
+
+    fn candidates(db: &Database, terms: &str, regex: bool, match_case: bool) -> Vec<String> {
+        let mut keys = db.search_candidates(terms, regex, match_case).expect("query should narrow").into_iter().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn trigrams_fold_ascii_case_only() {
+        assert_eq!(trigrams(b"aBcAbC"), sorted(vec![pack(b"abc"), pack(b"bca"), pack(b"cab")]));
+        assert!(trigrams(b"ab").is_empty());
+        // Non-ASCII bytes are kept as they are
+        assert_eq!(trigrams("É".as_bytes()), Vec::<Trigram>::new());
+        assert_eq!(trigrams("xÉ".as_bytes()), vec![u32::from_le_bytes([b'x', 0xc3, 0x89, 0])]);
+    }
+
+    #[test]
+    fn query_trigrams_skip_what_case_folding_could_change() {
+        assert_eq!(sorted(query_trigrams(b"kelvin", true)), trigrams(b"kelvin"));
+        // The Kelvin sign folds to k
+        assert_eq!(query_trigrams(b"kelvin", false), vec![pack(b"elv"), pack(b"lvi"), pack(b"vin")]);
+        // And the long s to s
+        assert_eq!(query_trigrams(b"stops", false), vec![pack(b"top")]);
+        assert!(query_trigrams("café".as_bytes(), false).iter().all(|t| *t == pack(b"caf")));
+    }
+
+    #[test]
+    fn required_literals_skip_optional_parts() {
+        assert_eq!(required_literals("foo.*bar(baz)+q?xyz").unwrap(), vec![literal("foo"), literal("bar"), literal("baz"), literal("xyz")]);
+        assert_eq!(required_literals(r"\bhello\b").unwrap(), vec![literal("hello")]);
+        // `a` may be missing and `bc` alone is too short to narrow by
+        assert!(required_literals("a?bc").unwrap().is_empty());
+        assert_eq!(required_literals("xa?bcd").unwrap(), vec![literal("bcd")]);
+        assert!(required_literals("abc|def").unwrap().is_empty());
+        assert!(required_literals("(").is_none());
+    }
+
+    #[test]
+    fn case_folded_letters_are_still_found() {
+        let mut db = workspace("index-folding");
+        db.insert("kelvin.txt".to_string(), "20 \u{212a}elvin".to_string());
+        db.insert("long-s.txt".to_string(), "\u{17f}top here".to_string());
+        db.insert("other.txt".to_string(), "nothing".to_string());
+        for key in ["kelvin.txt", "long-s.txt", "other.txt"] {
+            db.save(key).unwrap();
+        }
+        assert_eq!(candidates(&db, "KELVIN", false, false), ["kelvin.txt"]);
+        assert_eq!(candidates(&db, "stop", false, false), ["long-s.txt"]);
+        assert!(candidates(&db, "stop", false, true).is_empty());
+    }
+
+    #[test]
+    fn refresh_picks_up_changes_on_disk() {
+        let mut db = workspace("index-refresh");
+        let dir = PathBuf::from(db.current_dir());
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.md"), "Hello World").unwrap();
+        fs::write(dir.join("sub/b.md"), "goodbye world").unwrap();
+        db.load().unwrap();
+        // Nothing is known until the first refresh, so everything is searched
+        assert_eq!(candidates(&db, "hello", false, false), ["a.md", "sub/b.md"]);
+        refresh(&mut db);
+        assert_eq!(candidates(&db, "hello", false, false), ["a.md"]);
+        assert_eq!(candidates(&db, "WORLD", false, false), ["a.md", "sub/b.md"]);
+        assert_eq!(candidates(&db, "Hello", false, true), ["a.md"]);
+        assert_eq!(candidates(&db, "good.*", true, false), ["sub/b.md"]);
+        assert!(db.search_candidates("go", false, false).is_none());
+        db.save_index().unwrap();
+
+        // Read back, entries are searched until checked against the files
+        let mut db = Database::new(dir.to_str().unwrap(), true);
+        db.load().unwrap();
+        fs::write(dir.join("a.md"), "goodbye too, and longer").unwrap();
+        assert_eq!(candidates(&db, "hello", false, false), ["a.md", "sub/b.md"]);
+        refresh(&mut db);
+        assert!(candidates(&db, "hello", false, false).is_empty());
+        assert_eq!(candidates(&db, "goodbye", false, false), ["a.md", "sub/b.md"]);
+
+        db.rename("a.md", "c.md");
+        assert_eq!(candidates(&db, "goodbye", false, false), ["c.md", "sub/b.md"]);
+        db.remove("sub/b.md");
+        assert_eq!(candidates(&db, "goodbye", false, false), ["c.md"]);
+    }
+
+    #[test]
+    fn scans_dont_undo_newer_saves() {
+        let mut db = workspace("index-race");
+        db.insert("a.md".to_string(), "old text".to_string());
+        db.save("a.md").unwrap();
+        let scan = db.index_scan();
+        fs::write(db.disk_path("a.md"), "changed outside").unwrap();
+        let scanned = scan.run();
+        db.insert("a.md".to_string(), "saved from the editor".to_string());
+        db.save("a.md").unwrap();
+        db.apply_index_scan(scanned);
+        assert_eq!(candidates(&db, "editor", false, false), ["a.md"]);
+        assert!(candidates(&db, "outside", false, false).is_empty());
+    }
+
+    // Notes of random words, with their text by key
+    fn many_notes(name: &str, count: usize) -> (Database, HashMap<String, String>) {
+        let mut db = workspace(name);
+        let mut seed = 7u64;
+        let mut word = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            format!("w{}", (seed >> 33) % 5000)
+        };
+        let mut texts = HashMap::new();
+        for n in 0..count {
+            let key = format!("notes/{}/{}.md", n % 100, n);
+            let text = (0..60).map(|_| word()).collect::<Vec<_>>().join(" ");
+            db.index.update(&key, text.as_bytes(), FileInfo::default());
+            db.data.insert(key.clone(), FileType::Text(TextFile {
+                path: db.disk_path(&key), content: None, dirty: false, read_only: false, info: FileInfo::default()
+            }));
+            texts.insert(key, text);
+        }
+        (db, texts)
+    }
+
+    fn pattern(terms: &str, regex: bool) -> Regex {
+        RegexBuilder::new(&if regex { terms.to_string() } else { regex::escape(terms) }).case_insensitive(true).build().unwrap()
+    }
+
+    #[test]
+    fn candidates_hold_every_match() {
+        let (db, texts) = many_notes("index-candidates", 2_000);
+        for (terms, regex) in [("w1234 w", false), ("w12", false), (r"w12\d\s+w3", true), (r"\bw499\b", true)] {
+            let pattern = pattern(terms, regex);
+            let candidates = db.search_candidates(terms, regex, false).unwrap();
+            let found = candidates.iter().filter(|key| pattern.is_match(&texts[*key])).count();
+            let expected = texts.values().filter(|text| pattern.is_match(text)).count();
+            assert!(expected > 0, "{} matches nothing", terms);
+            assert_eq!(found, expected, "{}", terms);
+        }
+    }
+
+    // The target is narrowing and verifying a search over tens of thousands
+    // of notes in under 100ms. Timing depends on the machine and the build,
+    // so it's only run when asked for, in release: cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn searching_twenty_thousand_notes_is_fast() {
+        let (db, texts) = many_notes("index-many", 20_000);
+        for (terms, regex) in [("w1234 w", false), (r"w4321\s+w12", true)] {
+            let started = Instant::now();
+            let pattern = pattern(terms, regex);
+            let found = db.search_candidates(terms, regex, false).unwrap().iter()
+                .filter(|key| pattern.is_match(&texts[*key]))
+                .count();
+            let elapsed = started.elapsed();
+            let expected = texts.values().filter(|text| pattern.is_match(text)).count();
+            assert_eq!(found, expected);
+            assert!(elapsed < Duration::from_millis(100), "{} took {:?}", terms, elapsed);
+        }
+    }
+}
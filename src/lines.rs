@@ -0,0 +1,397 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use slint::{Model, ModelRc, SharedString, VecModel, Weak};
+use crate::{AppWindow, EditorRow, StyledSpan, TextPosition};
+use crate::cache::Database;
+use crate::highlight::Pane;
+use crate::search::line_starts;
+use crate::tabs::select_text;
+
+// Where one pane's text is drawn. The editor wraps its text, so each line is
+// measured for how many rows it takes. Only the rows in view go to the UI,
+// which draws the line numbers and highlighting on them.
+#[derive(Default)]
+struct Layout {
+    lines: Vec<String>,
+    // How many rows each line takes, 0 while it needs measuring
+    rows: Vec<usize>,
+    // The row each line starts on
+    starts: Vec<usize>,
+    // The width the lines were measured at, and the rows in view
+    width: f32,
+    first: usize,
+    count: usize,
+    model: Rc<VecModel<EditorRow>>
+}
+
+impl Layout {
+    fn set_lines(&mut self, lines: Vec<String>) {
+        self.rows = vec![0; lines.len()];
+        self.lines = lines;
+    }
+
+    // Only the lines between what's unchanged at either end are replaced, so
+    // they're all that gets measured again on a keystroke
+    fn update(&mut self, text: &str) {
+        let new_lines = text.split('\n').collect::<Vec<_>>();
+        let prefix = self.lines.iter().zip(&new_lines).take_while(|(a, b)| a == *b).count();
+        let longest = self.lines.len().min(new_lines.len()) - prefix;
+        let suffix = self.lines.iter().rev().zip(new_lines.iter().rev()).take(longest).take_while(|(a, b)| a == *b).count();
+        let removed = self.lines.len() - prefix - suffix;
+        let inserted = new_lines.len() - prefix - suffix;
+        self.lines.splice(prefix..prefix + removed, new_lines[prefix..prefix + inserted].iter().map(|line| line.to_string()));
+        self.rows.splice(prefix..prefix + removed, (0..inserted).map(|_| 0));
+    }
+
+    fn view(&mut self, first: usize, count: usize, width: f32) {
+        if width != self.width {
+            self.width = width;
+            self.rows.fill(0);
+        }
+        self.first = first;
+        self.count = count;
+    }
+
+    fn show(&mut self, spans: Option<&[Vec<StyledSpan>]>, rows: impl Fn(&str) -> usize) {
+        for (line, count) in self.lines.iter().zip(self.rows.iter_mut()) {
+            if *count == 0 {
+                *count = rows(line).max(1);
+            }
+        }
+        self.starts = self.rows.iter()
+            .scan(0, |row, count| {
+                let start = *row;
+                *row += count;
+                Some(start)
+            })
+            .collect();
+
+        let last = self.first + self.count;
+        let mut shown = Vec::new();
+        let mut line = self.starts.partition_point(|&start| start <= self.first).saturating_sub(1);
+        while line < self.lines.len() && self.starts[line] < last {
+            let line_spans = spans.and_then(|spans| spans.get(line));
+            // Spans are only split where the line wraps when they need to be
+            let breaks = match line_spans {
+                Some(_) if self.rows[line] > 1 => row_starts(&self.lines[line], self.rows[line], &rows),
+                _ => vec![0],
+            };
+            for row in self.starts[line]..(self.starts[line] + self.rows[line]).min(last) {
+                if row < self.first {
+                    continue;
+                }
+                let in_line = row - self.starts[line];
+                let from = breaks.get(in_line).copied().unwrap_or(usize::MAX);
+                let to = breaks.get(in_line + 1).copied().unwrap_or(usize::MAX);
+                shown.push(EditorRow {
+                    row: row as i32,
+                    number: if in_line == 0 { line as i32 + 1 } else { 0 },
+                    spans: ModelRc::new(VecModel::from(line_spans.map(|spans| slice_spans(spans, from, to)).unwrap_or_default()))
+                });
+            }
+            line += 1;
+        }
+
+        // Rows that are already there are changed in place rather than made again
+        let count = shown.len();
+        for (i, row) in shown.into_iter().enumerate() {
+            match self.model.row_data(i) {
+                Some(old) if old.row == row.row && old.number == row.number && old.spans.iter().eq(row.spans.iter()) => {}
+                Some(_) => self.model.set_row_data(i, row),
+                None => self.model.push(row),
+            }
+        }
+        while self.model.row_count() > count {
+            self.model.remove(self.model.row_count() - 1);
+        }
+    }
+
+    // Line and column at x on a row of the text
+    fn locate(&self, row: usize, x: f32, width: impl Fn(&str) -> f32, rows: impl Fn(&str) -> usize) -> TextPosition {
+        if self.lines.is_empty() {
+            return TextPosition { line: 1, column: 1 };
+        }
+        let line = self.starts.partition_point(|&start| start <= row).clamp(1, self.lines.len()) - 1;
+        let count = self.rows.get(line).copied().unwrap_or(1);
+        let in_line = row.saturating_sub(self.starts.get(line).copied().unwrap_or(0));
+        let starts = if count > 1 { row_starts(&self.lines[line], count, &rows) } else { vec![0] };
+        TextPosition {
+            line: line as i32 + 1,
+            column: column_at(&self.lines[line], &starts, in_line, x, width) as i32
+        }
+    }
+}
+
+// Layout of each pane, shared with the UI's callbacks. Those can come while
+// the Database is borrowed, e.g. when selecting text moves the cursor.
+#[derive(Default)]
+pub struct Layouts {
+    main: RefCell<Layout>,
+    side: RefCell<Layout>
+}
+
+impl Layouts {
+    fn pane(&self, pane: Pane) -> &RefCell<Layout> {
+        match pane {
+            Pane::Main => &self.main,
+            Pane::Side => &self.side,
+        }
+    }
+
+    // Handed to the UI once, after that only its rows change
+    pub fn model(&self, pane: Pane) -> ModelRc<EditorRow> {
+        ModelRc::from(self.pane(pane).borrow().model.clone())
+    }
+
+    pub fn open(&self, pane: Pane, text: &str) {
+        self.pane(pane).borrow_mut().set_lines(text.split('\n').map(str::to_string).collect());
+    }
+
+    pub fn close(&self, pane: Pane) {
+        self.pane(pane).borrow_mut().set_lines(Vec::new());
+    }
+
+    pub fn update(&self, pane: Pane, text: &str) {
+        self.pane(pane).borrow_mut().update(text);
+    }
+}
+
+// Measures the lines that changed and hands the pane the rows in view
+pub fn show_rows(db: &Database, ui: &AppWindow, pane: Pane) {
+    let side = pane == Pane::Side;
+    let mut layout = db.layouts.pane(pane).borrow_mut();
+    layout.show(db.highlights.spans(pane), |text| ui.invoke_measure_rows(side, text.into()) as usize);
+    let count = layout.lines.len() as i32;
+    if side {
+        ui.set_side_line_count(count);
+    } else {
+        ui.set_editor_line_count(count);
+    }
+}
+
+// Where each row a line wraps to starts. Rows break after whole words, so a
+// char is on the row its word ends on.
+fn row_starts(line: &str, count: usize, rows: impl Fn(&str) -> usize) -> Vec<usize> {
+    let line = line.trim_end_matches('\r');
+    let chars = line.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    let row_of = |i: usize| {
+        let word_end = line[i..].find(char::is_whitespace).map_or(line.len(), |end| i + end);
+        rows(&line[..word_end]).saturating_sub(1)
+    };
+    let mut starts = vec![0];
+    for row in 1..count {
+        let from = chars.partition_point(|&i| i < starts[row - 1]);
+        let at = from + chars[from..].partition_point(|&i| row_of(i) < row);
+        starts.push(chars.get(at).copied().unwrap_or(line.len()));
+    }
+    starts
+}
+
+// The part of a line's spans from one byte offset to another
+fn slice_spans(spans: &[StyledSpan], from: usize, to: usize) -> Vec<StyledSpan> {
+    let mut at = 0;
+    spans.iter()
+        .filter_map(|span| {
+            let (start, end) = (at, at + span.text.len());
+            at = end;
+            let (from, to) = (from.max(start), to.min(end));
+            (from < to).then(|| StyledSpan { text: SharedString::from(&span.text[from - start..to - start]), kind: span.kind })
+        })
+        .collect()
+}
+
+// Column nearest x on the row of a line that starts at starts[row], counted
+// from one
+fn column_at(line: &str, starts: &[usize], row: usize, x: f32, width: impl Fn(&str) -> f32) -> usize {
+    let line = line.trim_end_matches('\r');
+    let start = starts.get(row).copied().unwrap_or(line.len()).min(line.len());
+    let end = starts.get(row + 1).copied().unwrap_or(line.len()).min(line.len());
+    let bounds = line[start..end].char_indices().map(|(i, _)| start + i).chain([end]).collect::<Vec<_>>();
+    let row_width = |j: usize| width(&line[start..bounds[j]]);
+    let after = bounds.partition_point(|&i| width(&line[start..i]) < x);
+    let nearest = if after == bounds.len() || (after > 0 && x - row_width(after - 1) < row_width(after) - x) {
+        after - 1
+    } else {
+        after
+    };
+    line[..bounds[nearest]].chars().count() + 1
+}
+
+// "12" or "12:5". Lines and columns past the end go to the last one.
+fn parse_target(target: &str) -> Option<(usize, usize)> {
+    let (line, column) = target.trim().split_once(':').unwrap_or((target.trim(), "1"));
+    Some((line.trim().parse().ok()?, column.trim().parse().ok()?))
+}
+
+fn offset_of(text: &str, line: usize, column: usize) -> usize {
+    let starts = line_starts(text);
+    let start = starts[line.clamp(1, starts.len()) - 1];
+    let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+    let line = text[start..end].trim_end_matches('\r');
+    start + line.char_indices().nth(column.max(1) - 1).map_or(line.len(), |(i, _)| i)
+}
+
+pub fn handle_lines(db: Rc<RefCell<Database>>, weak: Weak<AppWindow>) {
+    let ui = weak.unwrap();
+
+    let ui_handle = weak.clone();
+    let layouts = Rc::clone(&db.borrow().layouts);
+    ui.on_locate(move |side, row, x| {
+        let ui = ui_handle.unwrap();
+        let pane = if side { Pane::Side } else { Pane::Main };
+        layouts.pane(pane).borrow().locate(
+            row.max(0) as usize,
+            x,
+            |text| ui.invoke_measure_width(side, text.into()),
+            |text| ui.invoke_measure_rows(side, text.into()) as usize
+        )
+    });
+
+    let ui_handle = weak.clone();
+    ui.on_view_changed(move |side, first, count, width| {
+        let db = db.borrow();
+        let pane = if side { Pane::Side } else { Pane::Main };
+        db.layouts.pane(pane).borrow_mut().view(first.max(0) as usize, count.max(0) as usize, width);
+        show_rows(&db, &ui_handle.unwrap(), pane);
+    });
+
+    // Always in the main pane, the one the status bar shows
+    let ui_handle = weak.clone();
+    ui.on_go_to_line(move |target: SharedString| {
+        let ui = ui_handle.unwrap();
+        let Some((line, column)) = parse_target(target.as_str()) else { return false };
+        let offset = offset_of(&ui.invoke_get_current_box(), line, column);
+        select_text(&ui, offset, offset);
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenKind;
+
+    // Every char 10 wide, rows 10 chars long that break after whole words
+    fn width(text: &str) -> f32 {
+        text.chars().count() as f32 * 10.0
+    }
+
+    fn rows(text: &str) -> usize {
+        let mut rows = 1;
+        let mut row_len = 0;
+        for word in text.split(' ') {
+            if row_len > 0 && row_len + 1 + word.len() > 10 {
+                rows += 1;
+                row_len = word.len();
+            } else {
+                row_len += word.len() + if row_len > 0 { 1 } else { 0 };
+            }
+        }
+        rows
+    }
+
+    fn span(text: &str, kind: TokenKind) -> StyledSpan {
+        StyledSpan { text: text.into(), kind }
+    }
+
+    #[test]
+    fn layout_update_matches_the_text() {
+        let texts = ["a\nb\nc", "a\nbx\nc", "a\nc", "", "x\n\ny\n", "x\ny\n", "a\na\na", "a\na", "a\nb\na\nb\na"];
+        let mut layout = Layout::default();
+        layout.set_lines(texts[0].split('\n').map(str::to_string).collect());
+        for text in &texts[1..] {
+            layout.update(text);
+            assert_eq!(layout.lines, text.split('\n').collect::<Vec<_>>(), "after {:?}", text);
+            assert_eq!(layout.rows.len(), layout.lines.len(), "after {:?}", text);
+        }
+    }
+
+    #[test]
+    fn only_rows_in_view_are_shown() {
+        let mut layout = Layout::default();
+        layout.set_lines(vec!["one".into(), "alpha beta gamma delta".into(), "two".into(), "three".into()]);
+        layout.view(2, 3, 100.0);
+        layout.show(None, rows);
+        let shown = layout.model.iter().map(|row| (row.row, row.number)).collect::<Vec<_>>();
+        assert_eq!(shown, [(2, 0), (3, 0), (4, 3)]);
+
+        layout.view(0, 2, 100.0);
+        layout.show(None, rows);
+        let shown = layout.model.iter().map(|row| (row.row, row.number)).collect::<Vec<_>>();
+        assert_eq!(shown, [(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn wrapped_highlighting_is_split_across_rows() {
+        let mut layout = Layout::default();
+        layout.set_lines(vec!["alpha beta gamma delta".into()]);
+        let spans = vec![vec![
+            span("alpha", TokenKind::Keyword),
+            span(" beta gam", TokenKind::Plain),
+            span("ma delta", TokenKind::String)
+        ]];
+        layout.view(0, 5, 100.0);
+        layout.show(Some(&spans), rows);
+        let shown = layout.model.iter()
+            .map(|row| row.spans.iter().map(|span| (span.text.to_string(), span.kind)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(shown, [
+            vec![("alpha".to_string(), TokenKind::Keyword), (" beta ".to_string(), TokenKind::Plain)],
+            vec![("gam".to_string(), TokenKind::Plain), ("ma ".to_string(), TokenKind::String)],
+            vec![("delta".to_string(), TokenKind::String)],
+        ]);
+    }
+
+    #[test]
+    fn rows_start_after_whole_words() {
+        let line = "alpha beta gamma delta";
+        assert_eq!(row_starts(line, rows(line), rows), [0, 11, 17]);
+        assert_eq!(row_starts("short\r", 1, rows), [0]);
+    }
+
+    #[test]
+    fn spans_slice_by_byte_offsets() {
+        let spans = [span("ab", TokenKind::Keyword), span("cdé", TokenKind::Plain)];
+        let sliced = slice_spans(&spans, 1, 4);
+        assert_eq!(sliced.iter().map(|span| span.text.as_str()).collect::<Vec<_>>(), ["b", "cd"]);
+        assert!(slice_spans(&spans, 6, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(parse_target(" 12 "), Some((12, 1)));
+        assert_eq!(parse_target("3:4"), Some((3, 4)));
+        assert_eq!(parse_target("3 : 4"), Some((3, 4)));
+        assert_eq!(parse_target("x"), None);
+        assert_eq!(parse_target("3:"), None);
+    }
+
+    #[test]
+    fn offsets_clamp_to_the_text() {
+        let text = "ab\ncé\r\nxyz";
+        assert_eq!(offset_of(text, 2, 2), 4);
+        assert_eq!(offset_of(text, 2, 99), 6);
+        assert_eq!(offset_of(text, 0, 1), 0);
+        assert_eq!(offset_of(text, 99, 2), 9);
+    }
+
+    #[test]
+    fn columns_on_wrapped_rows() {
+        // Wraps to "alpha beta ", "gamma " and "delta"
+        let line = "alpha beta gamma delta";
+        let starts = row_starts(line, rows(line), rows);
+        assert_eq!(column_at(line, &starts, 0, 0.0, width), 1);
+        assert_eq!(column_at(line, &starts, 0, 34.0, width), 4);
+        assert_eq!(column_at(line, &starts, 1, 0.0, width), 12);
+        assert_eq!(column_at(line, &starts, 1, 26.0, width), 15);
+        assert_eq!(column_at(line, &starts, 2, 999.0, width), 23);
+        assert_eq!(column_at(line, &starts, 5, 0.0, width), 23);
+    }
+
+    #[test]
+    fn columns_count_chars() {
+        assert_eq!(column_at("cé\r", &[0], 0, 999.0, width), 3);
+        assert_eq!(column_at("éé", &[0], 0, 12.0, width), 2);
+        assert_eq!(column_at("", &[0], 0, 5.0, width), 1);
+    }
+}
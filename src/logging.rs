@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+const LOG_FILE: &str = "editor.log";
+// Rotate once the file reaches this size, keeping this many old files
+const MAX_SIZE: u64 = 1024 * 1024;
+const KEEP_FILES: usize = 3;
+// Lines kept in memory for the log panel in Settings
+const RECENT_LINES: usize = 500;
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64
+}
+
+// Writes every record to stdout, a ring buffer for the log panel and, once the
+// data directory is known, a rotating log file inside it
+pub struct Logger {
+    recent: Mutex<VecDeque<String>>,
+    file: Mutex<Option<LogFile>>
+}
+
+static LOGGER: Logger = Logger {
+    recent: Mutex::new(VecDeque::new()),
+    file: Mutex::new(None)
+};
+
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(if cfg!(debug_assertions) { LevelFilter::Debug } else { LevelFilter::Info });
+    }
+}
+
+// Starts writing to the log file in `dir`. Lines logged before this are only
+// in memory, so they're written out first.
+pub fn attach_file(dir: &Path) -> io::Result<()> {
+    create_dir_all(dir)?;
+    let path = dir.join(LOG_FILE);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    let mut log_file = LogFile { path, file, size };
+
+    let mut current = LOGGER.file.lock().unwrap();
+    if current.is_none() {
+        for line in LOGGER.recent.lock().unwrap().iter() {
+            log_file.write_line(line)?;
+        }
+    }
+    *current = Some(log_file);
+    Ok(())
+}
+
+pub fn recent_lines() -> String {
+    LOGGER.recent.lock().unwrap().iter().cloned().collect::<Vec<_>>().join("\n")
+}
+
+impl LogFile {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size >= MAX_SIZE {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // editor.log becomes editor.log.1, editor.log.1 becomes editor.log.2 and so
+    // on, dropping the oldest
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP_FILES).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+        rename(&self.path, numbered(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let module = record.module_path().and_then(|m| m.rsplit("::").next()).unwrap_or("");
+        let line = format!("{} {:<5} {}: {}", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), module, record.args());
+        if record.level() <= Level::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+        drop(recent);
+
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            // Nowhere left to report a failing log file, so it's only mentioned on stderr
+            if let Err(e) = file.write_line(&line) {
+                eprintln!("Couldn't write to {}: {}", file.path.display(), e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.file.flush();
+        }
+    }
+}
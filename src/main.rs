@@ -2,68 +2,140 @@
 mod cache;
 mod hooks;
 mod search_hooks;
+mod search;
+mod find_bar;
+mod tabs;
+mod panes;
+mod index;
+mod history;
+mod highlight;
+mod lines;
+mod quick_open;
 mod state;
 mod file_types;
-
-use std::cell::{RefCell, RefMut};
-use std::path::Path;
+mod sorting;
+mod fuzzy;
+mod operations;
+mod file_tree;
+mod notifications;
+mod logging;
+
+use std::cell::RefCell;
 use std::rc::Rc;
-use slint::{CloseRequestResponse, Image, Model, SharedString, VecModel, Weak};
+use log::info;
+use slint::{CloseRequestResponse, SharedString, Weak};
 use crate::cache::Database;
-use crate::file_types::FileType;
-use crate::hooks::{handle_change_background_image, handle_change_dir, handle_click_file_tree, handle_close, handle_close_popups, handle_delete, handle_new_file_button, handle_rename, handle_shortcuts, handle_textbox_edit};
-use crate::search_hooks::{on_move_down, on_pressed_enter, on_search};
+use crate::hooks::{handle_change_background_image, handle_change_dir, handle_click_file_tree, handle_close, handle_close_popups, handle_delete, handle_filter, handle_follow_symlinks, handle_log_panel, handle_new_file_button, handle_search_result_limit, load_background_image, load_workspace, handle_bulk_actions, handle_rename, handle_save_failures, handle_select, handle_shortcuts, handle_sort_mode, handle_textbox_edit, handle_keep_history, handle_relative_numbers, handle_text_history, save_before_exit};
+use crate::file_tree::FileTreeModel;
+use crate::find_bar::on_find_bar;
+use crate::highlight::Pane;
+use crate::lines::handle_lines;
+use crate::notifications::{handle_notifications, Notifier};
+use crate::panes::handle_panes;
+use crate::quick_open::on_quick_open;
+use crate::search_hooks::{on_apply_replace, on_move_result, on_pressed_enter, on_search, on_search_history, on_search_in_folder, on_toggle_result, refresh_index};
 use crate::state::State;
+use crate::tabs::{handle_tabs, update_tabs};
 
 slint::include_modules!();
 
-static mut CURRENT_FILE: Option<String> = None;
-
 fn main() -> Result<(), slint::PlatformError> {
 
-    let state = Rc::new(RefCell::new(State::new().read().expect("Loaded state")));
+    logging::init();
+    let notifier = Notifier::new();
+
+    // A broken state file shouldn't keep the editor from starting
+    let state = Rc::new(RefCell::new(State::new().read().unwrap_or_else(|e| {
+        notifier.error("Couldn't read settings, using the defaults", e);
+        State::new()
+    })));
 
-    let db = Rc::new(RefCell::new(Database::new(&state.borrow_mut().data_dir)));
-    db.borrow_mut().load().expect("Failed to load db");
+    let db = Rc::new(RefCell::new(Database::new(&state.borrow().data_dir, state.borrow().follow_symlinks)));
+    db.borrow_mut().sort_mode = state.borrow().sort_mode;
+    db.borrow_mut().folders_first = state.borrow().folders_first;
+    db.borrow_mut().keep_history = state.borrow().keep_history;
 
     let ui = AppWindow::new()?;
+    handle_notifications(Rc::clone(&notifier), ui.as_weak());
+    if let Err(e) = logging::attach_file(&db.borrow().meta_dir()) {
+        notifier.error("Couldn't open the log file", e);
+    }
 
     let clone = Rc::clone(&db);
     ui.as_weak().unwrap().set_current_dir(SharedString::from(clone.borrow_mut().current_dir()));
-    let bg = &state.borrow_mut().background_image_path.clone().unwrap();
-    ui.as_weak().unwrap().set_current_background(SharedString::from(bg));
-
-    ui.invoke_set_background_image(Image::load_from_path(Path::new(bg)).unwrap());
+    ui.set_follow_symlinks(state.borrow().follow_symlinks);
+    ui.set_sort_mode(SharedString::from(state.borrow().sort_mode.name()));
+    ui.set_folders_first(state.borrow().folders_first);
+    ui.set_search_result_limit(state.borrow().search_result_limit as i32);
+    ui.set_keep_history(state.borrow().keep_history);
+    ui.set_relative_numbers(state.borrow().relative_numbers);
+    ui.set_editor_rows(db.borrow().layouts.model(Pane::Main));
+    ui.set_side_rows(db.borrow().layouts.model(Pane::Side));
+    ui.set_search_options(SearchOptions::from(&state.borrow().search));
+
+    let bg = state.borrow().background_image_path.clone();
+    if let Some(bg) = bg {
+        if load_background_image(&ui, &notifier, &bg) {
+            ui.set_current_background(SharedString::from(bg));
+        } else {
+            state.borrow_mut().background_image_path = None;
+        }
+    }
 
-    let model = Rc::new(slint::VecModel::from(vec![]));
-    build_file_tree(Rc::clone(&db), model.clone());
+    let model = Rc::new(FileTreeModel::new());
+    load_workspace(&db, &model, &notifier);
     ui.set_files(model.clone().into());
-    handle_textbox_edit(Rc::clone(&db), ui.as_weak());
+    handle_textbox_edit(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
     handle_click_file_tree(Rc::clone(&db), Rc::clone(&state), model.clone(), ui.as_weak());
-    handle_new_file_button(Rc::clone(&db), Rc::clone(&state), model.clone(), ui.as_weak());
-    handle_window_events(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
-    handle_rename(Rc::clone(&db), model.clone(), ui.as_weak());
-    handle_delete(Rc::clone(&db), Rc::clone(&state), model.clone(), ui.as_weak());
-    handle_close(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    handle_new_file_button(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_window_events(Rc::clone(&db), Rc::clone(&state), Rc::clone(&notifier), ui.as_weak());
+    handle_rename(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_delete(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_close(Rc::clone(&db), Rc::clone(&state), Rc::clone(&notifier), ui.as_weak());
     handle_shortcuts(ui.as_weak());
+    handle_text_history(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
     handle_close_popups(ui.as_weak());
-    handle_change_background_image(Rc::clone(&state), ui.as_weak());
-    handle_change_dir(Rc::clone(&db), Rc::clone(&state), model.clone(), ui.as_weak());
-
-    on_search(Rc::clone(&db), ui.as_weak());
+    handle_log_panel(ui.as_weak());
+    handle_change_background_image(Rc::clone(&state), Rc::clone(&notifier), ui.as_weak());
+    handle_change_dir(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_follow_symlinks(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_save_failures(Rc::clone(&db), Rc::clone(&state), Rc::clone(&notifier), ui.as_weak());
+    handle_sort_mode(Rc::clone(&db), Rc::clone(&state), model.clone(), ui.as_weak());
+    handle_keep_history(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    handle_relative_numbers(Rc::clone(&state), ui.as_weak());
+    handle_search_result_limit(Rc::clone(&state), ui.as_weak());
+    handle_filter(Rc::clone(&db), model.clone(), ui.as_weak());
+    handle_select(Rc::clone(&db), model.clone(), ui.as_weak());
+    handle_bulk_actions(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+
+    on_search(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    on_search_in_folder(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    on_search_history(Rc::clone(&state), ui.as_weak());
+    refresh_index(Rc::clone(&db));
+    on_find_bar(Rc::clone(&notifier), ui.as_weak());
     on_pressed_enter(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
-    on_move_down(ui.as_weak());
+    on_move_result(ui.as_weak());
+    on_toggle_result(ui.as_weak());
+    on_quick_open(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    on_apply_replace(Rc::clone(&db), Rc::clone(&state), model.clone(), Rc::clone(&notifier), ui.as_weak());
+    handle_tabs(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    handle_panes(Rc::clone(&db), Rc::clone(&state), ui.as_weak());
+    handle_lines(Rc::clone(&db), ui.as_weak());
+
+    // Brings back the tabs and split open when the editor was last closed
+    update_tabs(&mut db.borrow_mut(), &mut state.borrow_mut(), &ui, &[]);
 
     ui.run()
 }
 
-fn handle_window_events(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, weak: Weak<AppWindow>) {
+fn handle_window_events(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, notifier: Rc<Notifier>, weak: Weak<AppWindow>) {
     let ui_handle = weak.clone();
     ui_handle.unwrap().window().on_close_requested(move || {
-        println!("Window close requested");
-        open_file(&mut db.borrow_mut(), &mut state.borrow_mut(), ui_handle.clone(), None);
-        db.borrow().save_all().expect("Failed to save all files on exit!");
-        return CloseRequestResponse::HideWindow;
+        info!("Window close requested");
+        if save_before_exit(&mut db.borrow_mut(), &mut state.borrow_mut(), &notifier, &ui_handle.unwrap()) {
+            return CloseRequestResponse::HideWindow;
+        }
+        return CloseRequestResponse::KeepWindowShown;
     });
     let ui_handle = weak.clone();
     ui_handle.unwrap().on_mouse_move(move |delta_x, delta_y| {
@@ -80,72 +152,6 @@ fn handle_window_events(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, we
     });
 }
 
-fn build_file_tree(db: Rc<RefCell<Database>>, model: Rc<VecModel<FileTreeItemData>>) {
-    let mut vector: Vec<FileTreeItemData> = Vec::new();
-
-    let cl = Rc::clone(&db);
-    let binding = &cl.borrow_mut();
-
-    let mut keys = binding.keys().clone();
-    keys.sort();
-
-    let mut paths_added: Vec<String> = Vec::new();
-    for key in keys {
-        push_files(binding, &mut vector, &mut paths_added, String::from(""), key.clone(), 0);
-    }
-
-    model.set_vec(vector);
-
-}
-
-fn push_files(db: &RefMut<Database>, vector: &mut Vec<FileTreeItemData>, paths_added: &mut Vec<String>, prefix: String, current: String, depth: i32) {
-    let split = current.split("/").collect::<Vec<_>>();
-    if split.clone().len() > 1 {
-
-        let mut new_prefix = prefix.clone().trim().to_string();
-        if prefix.is_empty() {
-            new_prefix.push_str(format!("{}", split[0]).as_str());
-        } else {
-            new_prefix.push_str(format!("/{}", split[0]).as_str());
-        }
-
-        let len = vector.clone().len();
-
-        let full_path = if prefix.trim().is_empty() { split[0].to_string().trim().to_string() } else { format!("{}/{}", prefix, split[0]).to_string().trim().to_string() };
-        if !full_path.is_empty() && !paths_added.contains(&full_path) {
-            vector.push(FileTreeItemData {
-                name: SharedString::from(split[0]),
-                index: len as i32,
-                full_path: SharedString::from(full_path.clone()),
-                ident: depth,
-                open: false,
-                r#type: SharedString::from("folder")
-            });
-            paths_added.push(full_path.clone());
-        }
-
-        if !db.collapsed(full_path.as_str()) {
-            return push_files(db, vector, paths_added, new_prefix, split.iter().skip(1).cloned().collect::<Vec<_>>().join("/"), depth + 1)
-        }
-    } else {
-        let len = vector.clone().len();
-        let full_path = if prefix.trim().is_empty() { current.to_string().trim().to_string() } else { format!("{}/{}", prefix, current).to_string().trim().to_string() };
-        if !full_path.is_empty() && !paths_added.contains(&full_path) {
-            vector.push(FileTreeItemData {
-                name: SharedString::from(current.clone()),
-                index: len as i32,
-                full_path: SharedString::from(full_path.clone()),
-                ident: depth,
-                open: false,
-                r#type: SharedString::from("file")
-            });
-
-            paths_added.push(full_path.clone());
-        }
-    }
-}
-
-
 fn remove_invalid_dirs(str: String) -> String {
     return if str.starts_with("/") {
         remove_invalid_dirs(str[1..].to_string())
@@ -155,28 +161,3 @@ fn remove_invalid_dirs(str: String) -> String {
         str
     }
 }
-
-
-fn open_file(db: &mut Database, state: &mut State, ui_handle: Weak<AppWindow>, file: Option<String>) {
-    unsafe {
-        let ui = ui_handle.unwrap();
-        if CURRENT_FILE.is_some() {
-            db.insert(CURRENT_FILE.clone().unwrap().as_str().to_owned().clone(), ui.invoke_get_current_box().as_str().to_owned().clone());
-        }
-        if file.is_some() {
-            let f = file.unwrap();
-            for x in 0..ui.get_files().row_count() {
-                let mut a = ui.get_files().row_data(x).unwrap();
-                a.open = ui.get_files().row_data(x).unwrap().full_path == f;
-                ui.get_files().set_row_data(x, a);
-            }
-            if let Some(FileType::Text(ref mut text_file)) = db.get_file(f.as_str()) {
-                ui.invoke_set_open_file(SharedString::from(f.clone()), SharedString::from(text_file.get_contents()));
-                CURRENT_FILE = Some(f.clone());
-                state.last_open_file = Some(f.clone());
-            }
-        } else {
-            CURRENT_FILE = None;
-        }
-    }
-}
\ No newline at end of file
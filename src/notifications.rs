@@ -0,0 +1,92 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::{Rc, Weak as RcWeak};
+use std::time::Duration;
+use log::error;
+use slint::{Model, ModelRc, SharedString, Timer, VecModel, Weak};
+use crate::{AppWindow, Notification};
+
+// Errors stay until dismissed, everything else fades out on its own
+const INFO_TIMEOUT: Duration = Duration::from_secs(4);
+
+// Owns the toasts shown in the corner of the window and the actions behind
+// their buttons
+pub struct Notifier {
+    toasts: Rc<VecModel<Notification>>,
+    actions: RefCell<HashMap<i32, Box<dyn Fn()>>>,
+    next_id: Cell<i32>
+}
+
+impl Notifier {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Notifier {
+            toasts: Rc::new(VecModel::default()),
+            actions: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0)
+        })
+    }
+
+    pub fn model(&self) -> ModelRc<Notification> {
+        self.toasts.clone().into()
+    }
+
+    pub fn info(self: &Rc<Self>, message: impl Display) {
+        let id = self.push(message.to_string(), String::new(), false, String::new());
+        let notifier: RcWeak<Self> = Rc::downgrade(self);
+        Timer::single_shot(INFO_TIMEOUT, move || {
+            if let Some(notifier) = notifier.upgrade() {
+                notifier.dismiss(id);
+            }
+        });
+    }
+
+    pub fn error(&self, message: impl Display, detail: impl Display) {
+        error!("{}: {}", message, detail);
+        self.push(message.to_string(), detail.to_string(), true, String::new());
+    }
+
+    // An error with a button that runs `action`. The toast goes away once the
+    // button is pressed, the action can raise a new one if it fails again.
+    pub fn error_with_action(&self, message: impl Display, detail: impl Display, label: &str, action: impl Fn() + 'static) {
+        error!("{}: {}", message, detail);
+        let id = self.push(message.to_string(), detail.to_string(), true, label.to_string());
+        self.actions.borrow_mut().insert(id, Box::new(action));
+    }
+
+    pub fn dismiss(&self, id: i32) {
+        self.actions.borrow_mut().remove(&id);
+        if let Some(row) = self.toasts.iter().position(|n| n.id == id) {
+            self.toasts.remove(row);
+        }
+    }
+
+    pub fn run_action(&self, id: i32) {
+        let action = self.actions.borrow_mut().remove(&id);
+        self.dismiss(id);
+        if let Some(action) = action {
+            action();
+        }
+    }
+
+    fn push(&self, message: String, detail: String, error: bool, action: String) -> i32 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.toasts.push(Notification {
+            id,
+            message: SharedString::from(message),
+            detail: SharedString::from(detail),
+            error,
+            action: SharedString::from(action)
+        });
+        id
+    }
+}
+
+pub fn handle_notifications(notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
+    let ui = ui_handle.unwrap();
+    ui.set_notifications(notifier.model());
+    let clone = Rc::clone(&notifier);
+    ui.on_dismiss_notification(move |id| clone.dismiss(id));
+    ui.on_notification_action(move |id| notifier.run_action(id));
+}
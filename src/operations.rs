@@ -0,0 +1,505 @@
+use std::fs::{self, create_dir_all, remove_dir, remove_file};
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::cache::Database;
+use crate::file_types::FileType;
+
+// Enough of a deleted file to put it back exactly as it was
+pub struct DeletedFile {
+    key: String,
+    path: PathBuf,
+    bytes: Vec<u8>
+}
+
+// A file's text on both sides of a search and replace
+pub struct ReplacedFile {
+    key: String,
+    before: String,
+    after: String
+}
+
+// A file operation that can be undone as a whole, however many files it touched
+pub enum Operation {
+    Delete(Vec<DeletedFile>),
+    Move(Vec<(String, String)>),
+    Copy(Vec<(String, String)>),
+    Export(Vec<(String, PathBuf)>),
+    Replace(Vec<ReplacedFile>)
+}
+
+impl Operation {
+    pub fn describe(&self) -> String {
+        let (verb, count) = match self {
+            Operation::Delete(files) => ("delete", files.len()),
+            Operation::Move(pairs) => ("move", pairs.len()),
+            Operation::Copy(pairs) => ("copy", pairs.len()),
+            Operation::Export(pairs) => ("export", pairs.len()),
+            Operation::Replace(files) => ("replace in", files.len()),
+        };
+        format!("{} {} file{}", verb, count, if count == 1 { "" } else { "s" })
+    }
+
+    // Keys that changed name, so the editor can follow the open file
+    pub fn renames(&self) -> Vec<(String, String)> {
+        match self {
+            Operation::Move(pairs) => pairs.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct OperationLog {
+    undo: Vec<Operation>,
+    redo: Vec<Operation>
+}
+
+impl OperationLog {
+    pub fn push(&mut self, operation: Operation) {
+        self.undo.push(operation);
+        self.redo.clear();
+    }
+
+    pub fn undo_label(&self) -> Option<String> {
+        self.undo.last().map(|op| format!("Undo {}", op.describe()))
+    }
+
+    pub fn redo_label(&self) -> Option<String> {
+        self.redo.last().map(|op| format!("Redo {}", op.describe()))
+    }
+}
+
+impl Database {
+    // Turns selected tree paths into file keys. A selected folder stands for
+    // every file below it.
+    pub fn expand_selection(&self, paths: &[String]) -> Vec<String> {
+        let mut keys = self.data.iter()
+            .filter(|(key, file)| {
+                matches!(file, FileType::Text(_)) && paths.iter().any(|p| *key == p || key.starts_with(&format!("{}/", p)))
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    // Drops paths that sit inside another selected folder, they move along with it
+    fn top_level(paths: &[String]) -> Vec<String> {
+        paths.iter()
+            .filter(|p| !paths.iter().any(|other| p.starts_with(&format!("{}/", other))))
+            .cloned()
+            .collect()
+    }
+
+    // Maps every file under `path` to the same place below `root`
+    fn relocate(&self, path: &str, root: &str) -> Vec<(String, String)> {
+        self.expand_selection(&[path.to_string()]).into_iter()
+            .map(|key| {
+                let target = format!("{}{}", root, &key[path.len()..]);
+                (key, target)
+            })
+            .collect()
+    }
+
+    // Places each (path, folder) pair one at a time, so names picked by
+    // get_unique_key already see the files placed before them. `copy` keeps
+    // the originals and allows a path to be placed next to itself.
+    fn place(&mut self, items: &[(String, String)], copy: bool) -> io::Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut result = Ok(());
+        for (path, folder) in items {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            let folder = folder.trim_matches('/');
+            let root = if folder.is_empty() { name.to_string() } else { format!("{}/{}", folder, name) };
+            if !copy && root == *path { continue; }
+            let root = self.get_unique_key(&root);
+
+            let step = self.relocate(path, &root);
+            let done = if copy { self.do_copy(&step) } else { self.do_move(&step) };
+            if let Err(e) = done {
+                result = Err(e);
+                break;
+            }
+            pairs.extend(step);
+        }
+        // Whatever made it to disk before a failure can still be undone
+        if !pairs.is_empty() {
+            self.operations.push(if copy { Operation::Copy(pairs.clone()) } else { Operation::Move(pairs.clone()) });
+        }
+        result.map(|_| pairs)
+    }
+
+    pub fn delete_keys(&mut self, paths: &[String]) -> io::Result<()> {
+        let keys = self.expand_selection(paths);
+        let (deleted, result) = self.do_delete(&keys);
+        // Whatever made it off disk before a failure can still be undone
+        if !deleted.is_empty() {
+            self.operations.push(Operation::Delete(deleted));
+        }
+        result
+    }
+
+    pub fn move_keys(&mut self, paths: &[String], folder: &str) -> io::Result<Vec<(String, String)>> {
+        let items = Self::top_level(paths).into_iter().map(|p| (p, folder.to_string())).collect::<Vec<_>>();
+        self.place(&items, false)
+    }
+
+    pub fn copy_keys(&mut self, paths: &[String], folder: &str) -> io::Result<()> {
+        let items = Self::top_level(paths).into_iter().map(|p| (p, folder.to_string())).collect::<Vec<_>>();
+        self.place(&items, true).map(|_| ())
+    }
+
+    // Copies each path next to itself under a fresh name
+    pub fn duplicate_keys(&mut self, paths: &[String]) -> io::Result<()> {
+        let items = Self::top_level(paths).into_iter()
+            .map(|p| {
+                let parent = p.rfind('/').map(|i| p[..i].to_string()).unwrap_or_default();
+                (p, parent)
+            })
+            .collect::<Vec<_>>();
+        self.place(&items, true).map(|_| ())
+    }
+
+    // Renaming from the name box. Moves the file on disk right away so it
+    // doesn't depend on the next save, a file that was never saved is only
+    // re-keyed.
+    pub fn rename_key(&mut self, from: &str, to: &str) -> io::Result<()> {
+        if self.data.contains_key(to) || self.is_folder(to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to)));
+        }
+        let from_path = self.disk_path(from);
+        if from_path.exists() {
+            let to_path = Path::new(&self.current_dir()).join(to);
+            if let Some(parent) = to_path.parent() {
+                create_dir_all(parent)?;
+            }
+            fs::rename(&from_path, &to_path)?;
+            remove_empty_dirs(&from_path, Path::new(&self.current_dir()));
+        }
+        self.rename(from, to);
+        Ok(())
+    }
+
+    pub fn export_keys(&mut self, paths: &[String], dir: &Path) -> io::Result<()> {
+        let mut pairs = Vec::new();
+        for path in Self::top_level(paths) {
+            let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+            pairs.extend(self.relocate(&path, &name).into_iter().map(|(key, target)| (key, dir.join(target))));
+        }
+        let op = self.do_export(&pairs)?;
+        self.operations.push(op);
+        Ok(())
+    }
+
+    // Swaps in new text for each (key, text) pair and saves it, all undone together
+    pub fn replace_contents(&mut self, changes: Vec<(String, String)>) -> io::Result<()> {
+        let mut replaced = Vec::new();
+        let mut result = Ok(());
+        for (key, after) in changes {
+            let before = match self.data.get_mut(&key) {
+                Some(FileType::Text(file)) => file.get_contents(),
+                _ => continue,
+            };
+            if let Err(e) = self.write_text(&key, &after) {
+                result = Err(e);
+                break;
+            }
+            replaced.push(ReplacedFile { key, before, after });
+        }
+        if !replaced.is_empty() {
+            self.operations.push(Operation::Replace(replaced));
+        }
+        result
+    }
+
+    // Returns the keys renamed by the undo so open editors can follow them
+    pub fn undo_operation(&mut self) -> io::Result<Vec<(String, String)>> {
+        let Some(op) = self.operations.undo.pop() else { return Ok(Vec::new()) };
+        let redo = match op {
+            Operation::Delete(files) => {
+                for file in &files {
+                    if let Some(parent) = file.path.parent() {
+                        create_dir_all(parent)?;
+                    }
+                    fs::write(&file.path, &file.bytes)?;
+                    self.insert_existing(file.key.clone(), file.path.clone());
+                }
+                Operation::Delete(files)
+            }
+            Operation::Move(pairs) => {
+                let reversed = pairs.iter().map(|(from, to)| (to.clone(), from.clone())).collect::<Vec<_>>();
+                self.do_move(&reversed)?;
+                Operation::Move(pairs)
+            }
+            Operation::Copy(pairs) => {
+                for (_, to) in &pairs {
+                    self.remove_from_disk(to)?;
+                }
+                Operation::Copy(pairs)
+            }
+            Operation::Export(pairs) => {
+                for (_, target) in &pairs {
+                    remove_file(target)?;
+                }
+                Operation::Export(pairs)
+            }
+            Operation::Replace(files) => {
+                for file in &files {
+                    self.write_text(&file.key, &file.before)?;
+                }
+                Operation::Replace(files)
+            }
+        };
+        let renames = redo.renames().into_iter().map(|(from, to)| (to, from)).collect();
+        self.operations.redo.push(redo);
+        Ok(renames)
+    }
+
+    pub fn redo_operation(&mut self) -> io::Result<Vec<(String, String)>> {
+        let Some(op) = self.operations.redo.pop() else { return Ok(Vec::new()) };
+        let undo = match op {
+            Operation::Delete(files) => {
+                let keys = files.into_iter().map(|f| f.key).collect::<Vec<_>>();
+                let (deleted, result) = self.do_delete(&keys);
+                if let Err(e) = result {
+                    if !deleted.is_empty() {
+                        self.operations.undo.push(Operation::Delete(deleted));
+                    }
+                    return Err(e);
+                }
+                Operation::Delete(deleted)
+            }
+            Operation::Move(pairs) => self.do_move(&pairs)?,
+            Operation::Copy(pairs) => self.do_copy(&pairs)?,
+            Operation::Export(pairs) => self.do_export(&pairs)?,
+            Operation::Replace(files) => {
+                for file in &files {
+                    self.write_text(&file.key, &file.after)?;
+                }
+                Operation::Replace(files)
+            }
+        };
+        let renames = undo.renames();
+        self.operations.undo.push(undo);
+        Ok(renames)
+    }
+
+    // Stops at the first file that can't be deleted, the ones deleted
+    // before it are still handed back
+    fn do_delete(&mut self, keys: &[String]) -> (Vec<DeletedFile>, io::Result<()>) {
+        let mut deleted = Vec::new();
+        for key in keys {
+            let path = self.disk_path(key);
+            // Unsaved edits win over what's on disk, so undo brings those back too
+            let bytes = match self.data.get(key) {
+                Some(FileType::Text(file)) if file.dirty && file.content.is_some() => Ok(file.content.clone().unwrap().into_bytes()),
+                _ => fs::read(&path),
+            };
+            let removed = bytes.and_then(|bytes| self.remove_from_disk(key).map(|_| bytes));
+            match removed {
+                Ok(bytes) => deleted.push(DeletedFile { key: key.clone(), path, bytes }),
+                Err(e) => return (deleted, Err(e)),
+            }
+        }
+        (deleted, Ok(()))
+    }
+
+    fn do_move(&mut self, pairs: &[(String, String)]) -> io::Result<Operation> {
+        for (from, to) in pairs {
+            let from_path = self.disk_path(from);
+            let to_path = Path::new(&self.current_dir()).join(to);
+            if let Some(parent) = to_path.parent() {
+                create_dir_all(parent)?;
+            }
+            fs::rename(&from_path, &to_path)?;
+            self.rename(from, to);
+            remove_empty_dirs(&from_path, Path::new(&self.current_dir()));
+        }
+        Ok(Operation::Move(pairs.to_vec()))
+    }
+
+    fn do_copy(&mut self, pairs: &[(String, String)]) -> io::Result<Operation> {
+        for (from, to) in pairs {
+            let to_path = Path::new(&self.current_dir()).join(to);
+            self.write_copy(from, &to_path)?;
+            self.insert_existing(to.clone(), to_path);
+        }
+        Ok(Operation::Copy(pairs.to_vec()))
+    }
+
+    fn do_export(&mut self, pairs: &[(String, PathBuf)]) -> io::Result<Operation> {
+        for (key, target) in pairs {
+            self.write_copy(key, target)?;
+        }
+        Ok(Operation::Export(pairs.to_vec()))
+    }
+
+    // Copies straight from disk unless the file has unsaved edits
+    fn write_copy(&self, key: &str, target: &Path) -> io::Result<()> {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        match self.data.get(key) {
+            Some(FileType::Text(file)) if file.dirty && file.content.is_some() => fs::write(target, file.content.as_ref().unwrap()),
+            _ => fs::copy(self.disk_path(key), target).map(|_| ()),
+        }
+    }
+
+    fn write_text(&mut self, key: &str, text: &str) -> io::Result<()> {
+        self.insert(key.to_string(), text.to_string());
+        self.save(key)
+    }
+
+    fn remove_from_disk(&mut self, key: &str) -> io::Result<()> {
+        let path = self.disk_path(key);
+        remove_file(&path)?;
+        self.remove(key);
+        remove_empty_dirs(&path, Path::new(&self.current_dir()));
+        Ok(())
+    }
+}
+
+// Cleans up the folders a file left behind, stopping at the workspace root
+fn remove_empty_dirs(file: &Path, root: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir {
+        if d == root || remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::cache::test_support::workspace;
+    use super::*;
+
+    // A workspace holding each (key, text) pair as a file on disk
+    fn workspace_with(name: &str, files: &[(&str, &str)]) -> Database {
+        let mut db = workspace(name);
+        for (key, text) in files {
+            let path = Path::new(&db.current_dir()).join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, text).unwrap();
+            db.insert_existing(key.to_string(), path);
+        }
+        db
+    }
+
+    fn on_disk(db: &Database, key: &str) -> Option<String> {
+        fs::read_to_string(Path::new(&db.current_dir()).join(key)).ok()
+    }
+
+    fn keys(db: &Database) -> Vec<String> {
+        let mut keys = db.data.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn move_undo_redo() {
+        let mut db = workspace_with("ops-move", &[("a/one.txt", "1"), ("a/two.txt", "2"), ("b/keep.txt", "k")]);
+        let renames = db.move_keys(&["a".to_string()], "b").unwrap();
+        assert_eq!(renames, [("a/one.txt".to_string(), "b/a/one.txt".to_string()), ("a/two.txt".to_string(), "b/a/two.txt".to_string())]);
+        assert_eq!(keys(&db), ["b/a/one.txt", "b/a/two.txt", "b/keep.txt"]);
+        assert_eq!(on_disk(&db, "b/a/one.txt").as_deref(), Some("1"));
+        assert!(!Path::new(&db.current_dir()).join("a").exists());
+        assert_eq!(db.operations.undo_label().as_deref(), Some("Undo move 2 files"));
+
+        let renames = db.undo_operation().unwrap();
+        assert_eq!(renames[0], ("b/a/one.txt".to_string(), "a/one.txt".to_string()));
+        assert_eq!(keys(&db), ["a/one.txt", "a/two.txt", "b/keep.txt"]);
+        assert_eq!(on_disk(&db, "a/two.txt").as_deref(), Some("2"));
+        assert_eq!(db.operations.redo_label().as_deref(), Some("Redo move 2 files"));
+
+        db.redo_operation().unwrap();
+        assert_eq!(keys(&db), ["b/a/one.txt", "b/a/two.txt", "b/keep.txt"]);
+        assert!(db.operations.redo_label().is_none());
+    }
+
+    #[test]
+    fn delete_undo_brings_back_unsaved_edits() {
+        let mut db = workspace_with("ops-delete", &[("notes.txt", "saved"), ("other.txt", "o")]);
+        db.insert("notes.txt".to_string(), "edited".to_string());
+        db.delete_keys(&["notes.txt".to_string()]).unwrap();
+        assert_eq!(keys(&db), ["other.txt"]);
+        assert_eq!(on_disk(&db, "notes.txt"), None);
+
+        db.undo_operation().unwrap();
+        assert_eq!(keys(&db), ["notes.txt", "other.txt"]);
+        assert_eq!(on_disk(&db, "notes.txt").as_deref(), Some("edited"));
+
+        db.redo_operation().unwrap();
+        assert_eq!(on_disk(&db, "notes.txt"), None);
+        db.undo_operation().unwrap();
+        assert_eq!(on_disk(&db, "notes.txt").as_deref(), Some("edited"));
+    }
+
+    #[test]
+    fn a_failed_delete_can_undo_what_it_deleted() {
+        let mut db = workspace_with("ops-delete-failed", &[("a.txt", "a"), ("b.txt", "b")]);
+        fs::remove_file(Path::new(&db.current_dir()).join("b.txt")).unwrap();
+        assert!(db.delete_keys(&["a.txt".to_string(), "b.txt".to_string()]).is_err());
+        assert_eq!(keys(&db), ["b.txt"]);
+        assert_eq!(on_disk(&db, "a.txt"), None);
+        assert_eq!(db.operations.undo_label().as_deref(), Some("Undo delete 1 file"));
+
+        db.undo_operation().unwrap();
+        assert_eq!(keys(&db), ["a.txt", "b.txt"]);
+        assert_eq!(on_disk(&db, "a.txt").as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn copy_undo_redo() {
+        let mut db = workspace_with("ops-copy", &[("a.txt", "a"), ("dir/a.txt", "taken")]);
+        db.copy_keys(&["a.txt".to_string()], "dir").unwrap();
+        assert_eq!(keys(&db), ["a.txt", "dir/a 1.txt", "dir/a.txt"]);
+        assert_eq!(on_disk(&db, "dir/a 1.txt").as_deref(), Some("a"));
+
+        db.undo_operation().unwrap();
+        assert_eq!(keys(&db), ["a.txt", "dir/a.txt"]);
+        assert_eq!(on_disk(&db, "dir/a 1.txt"), None);
+
+        db.redo_operation().unwrap();
+        assert_eq!(on_disk(&db, "dir/a 1.txt").as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn a_new_operation_clears_redo() {
+        let mut db = workspace_with("ops-redo", &[("a.txt", "a"), ("b.txt", "b")]);
+        db.delete_keys(&["a.txt".to_string()]).unwrap();
+        db.undo_operation().unwrap();
+        assert!(db.operations.redo_label().is_some());
+        db.delete_keys(&["b.txt".to_string()]).unwrap();
+        assert!(db.operations.redo_label().is_none());
+        assert_eq!(db.redo_operation().unwrap(), []);
+    }
+
+    #[test]
+    fn duplicate_copies_on_disk_next_to_the_original() {
+        let mut db = workspace_with("ops-duplicate", &[("dir/a.txt", "a"), ("dir/b.txt", "b")]);
+        db.duplicate_keys(&["dir".to_string()]).unwrap();
+        assert_eq!(keys(&db), ["dir 1/a.txt", "dir 1/b.txt", "dir/a.txt", "dir/b.txt"]);
+        assert_eq!(on_disk(&db, "dir 1/b.txt").as_deref(), Some("b"));
+        db.undo_operation().unwrap();
+        assert_eq!(keys(&db), ["dir/a.txt", "dir/b.txt"]);
+    }
+
+    #[test]
+    fn renaming_moves_the_file_on_disk() {
+        let mut db = workspace_with("ops-rename", &[("a.txt", "a"), ("b.txt", "b")]);
+        db.rename_key("a.txt", "notes/c.txt").unwrap();
+        assert_eq!(keys(&db), ["b.txt", "notes/c.txt"]);
+        assert_eq!(on_disk(&db, "notes/c.txt").as_deref(), Some("a"));
+        assert_eq!(on_disk(&db, "a.txt"), None);
+        // Taken names are refused rather than overwritten
+        assert!(db.rename_key("b.txt", "notes/c.txt").is_err());
+        assert!(db.rename_key("b.txt", "notes").is_err());
+        assert_eq!(on_disk(&db, "b.txt").as_deref(), Some("b"));
+        // Back at the root, the emptied folder goes too
+        db.rename_key("notes/c.txt", "a.txt").unwrap();
+        assert!(!Path::new(&db.current_dir()).join("notes").exists());
+    }
+}
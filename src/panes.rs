@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use slint::{SharedString, Weak};
+use crate::AppWindow;
+use crate::cache::Database;
+use crate::file_types::FileType;
+use crate::highlight::Pane;
+use crate::lines::show_rows;
+use crate::state::State;
+use crate::tabs::{flush_buffer, show_tabs, write_back};
+
+// Where the second pane sits next to the main editor
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum Split {
+    #[default]
+    None,
+    Right,
+    Down
+}
+
+impl Split {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "Right" => Split::Right,
+            "Down" => Split::Down,
+            _ => Split::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Split::None => "None",
+            Split::Right => "Right",
+            Split::Down => "Down",
+        }
+    }
+}
+
+// Writes the side pane's text back, unless it shows the main pane's file which
+// is written back from there
+pub fn flush_side(db: &mut Database, state: &mut State, ui: &AppWindow) {
+    let current = state.current_file().map(str::to_string);
+    let Some(side) = state.side.as_mut() else { return };
+    side.scroll = ui.invoke_get_side_scroll();
+    if current.as_deref() != Some(side.key.as_str()) {
+        write_back(db, &side.key, ui.invoke_get_side_box().as_str());
+    }
+}
+
+// Loads the side pane's file into it, closing the split if that file is gone
+pub fn show_side(db: &mut Database, state: &mut State, ui: &AppWindow) {
+    let current = state.current_file().map(str::to_string);
+    let side = state.side.clone().filter(|_| state.split != Split::None);
+    match side.as_ref().and_then(|tab| db.get_file(&tab.key).map(|file| (tab, file))) {
+        Some((tab, FileType::Text(text_file))) => {
+            // The main pane may hold edits to the same file that aren't written back yet
+            let contents = match current {
+                Some(current) if current == tab.key => ui.invoke_get_current_box(),
+                _ => SharedString::from(text_file.get_contents()),
+            };
+            ui.invoke_set_side_file(SharedString::from(tab.key.as_str()), contents.clone(), text_file.read_only);
+            ui.invoke_set_side_scroll(tab.scroll);
+            db.history(&tab.key).sync(&contents);
+            ui.set_side_highlighted(db.highlights.open(Pane::Side, &tab.key, &contents));
+            db.layouts.open(Pane::Side, &contents);
+            show_rows(db, ui, Pane::Side);
+        }
+        _ => {
+            state.split = Split::None;
+            state.side = None;
+            db.highlights.close(Pane::Side);
+            db.layouts.close(Pane::Side);
+            ui.set_side_highlighted(false);
+            show_rows(db, ui, Pane::Side);
+        }
+    }
+    ui.set_split(SharedString::from(state.split.name()));
+}
+
+pub fn handle_panes(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, weak: Weak<AppWindow>) {
+    let ui = weak.unwrap();
+
+    // Splitting starts out with a second view of the open file
+    let (ui_handle, db_clone, state_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui.on_split_editor(move |mode: SharedString| {
+        let ui = ui_handle.unwrap();
+        let (mut db, mut state) = (db_clone.borrow_mut(), state_clone.borrow_mut());
+        flush_buffer(&mut db, &mut state, &ui);
+        let split = Split::from_name(mode.as_str());
+        if split == Split::None {
+            state.side = None;
+        } else if state.side.is_none() {
+            let Some(tab) = state.active_tab.and_then(|i| state.tabs.get(i)).cloned() else { return };
+            state.side = Some(tab);
+        }
+        state.split = split;
+        show_side(&mut db, &mut state, &ui);
+    });
+
+    let (ui_handle, db_clone, state_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui.on_open_to_side(move |index: i32| {
+        let ui = ui_handle.unwrap();
+        let (mut db, mut state) = (db_clone.borrow_mut(), state_clone.borrow_mut());
+        let Some(tab) = state.tabs.get(index as usize).cloned() else { return };
+        flush_buffer(&mut db, &mut state, &ui);
+        state.side = Some(tab);
+        if state.split == Split::None {
+            state.split = Split::Right;
+        }
+        show_side(&mut db, &mut state, &ui);
+    });
+
+    // Only called for a file the main pane isn't showing, the UI copies edits
+    // between the panes itself when they share one
+    let ui_handle = weak.clone();
+    ui.on_side_edited(move |text: SharedString| {
+        let mut db = db.borrow_mut();
+        let state = state.borrow();
+        let Some(side) = state.side.as_ref() else { return };
+        db.history(&side.key).record(&text);
+        db.highlights.update(Pane::Side, &text);
+        db.layouts.update(Pane::Side, &text);
+        show_rows(&db, &ui_handle.unwrap(), Pane::Side);
+        if !db.data.get(&side.key).is_some_and(|file| file.is_dirty()) {
+            db.mark_dirty(side.key.clone());
+            show_tabs(&db, &state, &ui_handle.unwrap());
+        }
+    });
+}
@@ -0,0 +1,82 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use slint::{SharedString, VecModel, Weak};
+use crate::{AppWindow, QuickOpenItem, Segment};
+use crate::cache::Database;
+use crate::file_types::FileType;
+use crate::fuzzy::fuzzy_score;
+use crate::state::State;
+use crate::tabs::open_tab;
+
+const MAX_ITEMS: usize = 50;
+// Added to the score of the most recently opened file, a bit less for each
+// one opened before it
+const RECENT_BOOST: i32 = 40;
+const RECENT_STEP: i32 = 2;
+
+struct Ranked {
+    key: String,
+    score: i32,
+    positions: Vec<usize>,
+    recent: bool
+}
+
+pub fn on_quick_open(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, weak: Weak<AppWindow>) {
+    let ui_handle = weak.clone();
+    let (db_clone, state_clone) = (Rc::clone(&db), Rc::clone(&state));
+    ui_handle.unwrap().on_quick_open_query(move |query: SharedString| {
+        let ranked = rank(&db_clone.borrow(), &state_clone.borrow().recent_files, query.as_str());
+        let items = ranked.into_iter().map(|r| QuickOpenItem {
+            path: SharedString::from(r.key.clone()),
+            segments: Rc::new(VecModel::from(segments(&r.key, &r.positions))).into(),
+            recent: r.recent
+        }).collect::<Vec<_>>();
+        ui_handle.unwrap().set_quick_open_items(Rc::new(VecModel::from(items)).into());
+    });
+
+    let ui_handle = weak.clone();
+    ui_handle.unwrap().on_open_quick_result(move |key: SharedString| {
+        let ui = ui_handle.unwrap();
+        ui.invoke_hide_popups();
+        open_tab(&mut db.borrow_mut(), &mut state.borrow_mut(), &ui, key.as_str());
+    });
+}
+
+// Best first. With no query the recent files come first, then everything else
+// by name.
+fn rank(db: &Database, recent: &[String], query: &str) -> Vec<Ranked> {
+    let boost = |key: &str| recent.iter().position(|k| k == key)
+        .map(|rank| (RECENT_BOOST - rank as i32 * RECENT_STEP).max(0));
+
+    let mut ranked = db.data.iter()
+        .filter(|(_, file)| matches!(file, FileType::Text(_)))
+        .filter_map(|(key, _)| {
+            let found = fuzzy_score(query, key)?;
+            let recent = boost(key);
+            Some(Ranked { key: key.clone(), score: found.score + recent.unwrap_or(0), positions: found.positions, recent: recent.is_some() })
+        })
+        .collect::<Vec<_>>();
+
+    if query.trim().is_empty() {
+        let order = |r: &Ranked| recent.iter().position(|k| *k == r.key).unwrap_or(usize::MAX);
+        ranked.sort_by(|a, b| order(a).cmp(&order(b)).then_with(|| a.key.cmp(&b.key)));
+    } else {
+        // Shorter paths win ties, they're usually what was meant
+        ranked.sort_by(|a, b| b.score.cmp(&a.score).then(a.key.len().cmp(&b.key.len())).then_with(|| a.key.cmp(&b.key)));
+    }
+    ranked.truncate(MAX_ITEMS);
+    ranked
+}
+
+// Splits the path into runs of matched and unmatched characters for highlighting
+fn segments(key: &str, positions: &[usize]) -> Vec<Segment> {
+    let mut runs: Vec<(String, bool)> = Vec::new();
+    for (i, c) in key.chars().enumerate() {
+        let is_match = positions.binary_search(&i).is_ok();
+        match runs.last_mut() {
+            Some((text, matched)) if *matched == is_match => text.push(c),
+            _ => runs.push((c.to_string(), is_match)),
+        }
+    }
+    runs.into_iter().map(|(text, matched)| Segment { text: SharedString::from(text), matched }).collect()
+}
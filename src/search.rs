@@ -0,0 +1,108 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
+
+// Plain text searches are escaped into a regex so both kinds share one code
+// path, and matching is done on the original text so offsets stay valid for it.
+// An invalid regex gives None.
+pub fn build_pattern(terms: &str, regex: bool, match_case: bool, whole_word: bool) -> Option<Regex> {
+    let mut pattern = if regex { terms.to_string() } else { regex::escape(terms) };
+    // Half boundaries only look outside the match, so terms that start or end
+    // with punctuation still match as a whole like they do in ripgrep
+    if whole_word {
+        pattern = format!(r"\b{{start-half}}(?:{})\b{{end-half}}", pattern);
+    }
+    RegexBuilder::new(&pattern).case_insensitive(!match_case).build().ok()
+}
+
+// Decides which files a search looks at from comma separated globs and the
+// folder it's scoped to. Include globs starting with ! exclude instead.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    scope: String
+}
+
+impl PathFilter {
+    // None when one of the globs doesn't parse
+    pub fn new(include: &str, exclude: &str, scope: &str) -> Option<Self> {
+        let globs = |text: &str, negated: bool| -> Vec<String> {
+            text.split(',')
+                .map(str::trim)
+                .filter(|glob| !glob.is_empty() && glob.starts_with('!') == negated)
+                .map(|glob| glob.trim_start_matches('!').to_string())
+                .collect()
+        };
+        let included = globs(include, false);
+        let mut excluded = globs(include, true);
+        excluded.extend(globs(exclude, false));
+        Some(PathFilter {
+            include: if included.is_empty() { None } else { Some(glob_set(&included)?) },
+            exclude: glob_set(&excluded)?,
+            scope: scope.trim_matches('/').to_string()
+        })
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let in_scope = self.scope.is_empty() || key.strip_prefix(&self.scope).is_some_and(|rest| rest.starts_with('/'));
+        in_scope && self.include.as_ref().is_none_or(|set| set.is_match(key)) && !self.exclude.is_match(key)
+    }
+}
+
+// A glob naming a folder also covers everything inside it
+fn glob_set(globs: &[String]) -> Option<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).ok()?);
+        builder.add(Glob::new(&format!("{}/**", glob.trim_end_matches('/'))).ok()?);
+    }
+    builder.build().ok()
+}
+
+// Byte ranges of every match. Empty regex matches are skipped since there's
+// nothing to show or replace for them.
+pub fn find_all(pattern: &Regex, text: &str) -> Vec<(usize, usize)> {
+    pattern.find_iter(text).filter(|m| !m.is_empty()).map(|m| (m.start(), m.end())).collect()
+}
+
+// What the match at start..end turns into, with $1 and ${name} expanded for
+// regex searches. None when the text there doesn't match exactly anymore,
+// e.g. because it was edited after searching.
+pub fn replacement_at(pattern: &Regex, text: &str, start: usize, end: usize, replacement: &str, regex: bool) -> Option<String> {
+    let caps = pattern.captures_at(text, start)?;
+    let whole = caps.get(0)?;
+    if whole.start() != start || whole.end() != end {
+        return None;
+    }
+    if !regex {
+        return Some(replacement.to_string());
+    }
+    let mut expanded = String::new();
+    caps.expand(replacement, &mut expanded);
+    Some(expanded)
+}
+
+// Byte offset where each line starts
+pub fn line_starts(contents: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(contents.match_indices('\n').map(|(i, _)| i + 1));
+    starts
+}
+
+// Zero based line and one based column, counted in characters like an editor would
+pub fn locate(contents: &str, starts: &[usize], offset: usize) -> (usize, usize) {
+    let line = starts.partition_point(|&start| start <= offset) - 1;
+    (line, contents[starts[line]..offset].chars().count() + 1)
+}
+
+// Applies (start, end, replacement) edits, which must be sorted and not overlap
+pub fn apply_edits(text: &str, edits: &[(usize, usize, String)]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, end, replacement) in edits {
+        result.push_str(&text[last..*start]);
+        result.push_str(replacement);
+        last = *end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
@@ -1,151 +1,589 @@
 use std::cell::RefCell;
-use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::debug;
 use regex::Regex;
-use slint::{ComponentHandle, Model, SharedString, Weak};
-use crate::{AppWindow, open_file, SearchResult};
+use slint::{ComponentHandle, Model, ModelRc, SharedString, Timer, VecModel, Weak};
+use crate::{AppWindow, SearchOptions, SearchQuery, SearchResult};
 use crate::cache::Database;
+use crate::file_tree::FileTreeModel;
+use crate::hooks::run_file_operation;
+use crate::notifications::Notifier;
+use crate::search::{apply_edits, build_pattern, find_all, line_starts, locate, replacement_at, PathFilter};
 use crate::file_types::FileType;
-use crate::state::State;
+use crate::index::Scanned;
+use crate::state::{SavedSearch, SearchSettings, State};
+use crate::tabs::{open_tab, reuse_tab, select_text};
+
+// Lines shown above and below each match
+const CONTEXT_LINES: usize = 1;
+const MAX_LINE_CHARS: usize = 120;
+// Characters kept in front of a match when a long line has to be cut
+const LEADING_CHARS: usize = 40;
+// Results reach the UI in batches of this many rows, or whatever was found
+// in this much time, whichever comes first
+const BATCH_SIZE: usize = 200;
+const BATCH_INTERVAL: Duration = Duration::from_millis(50);
+// Pause between looking for files changed outside the editor, doubled each
+// time nothing had changed up to the longest, and how often a running look
+// is checked on
+const INDEX_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const INDEX_REFRESH_LONGEST: Duration = Duration::from_secs(60);
+const INDEX_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 pub fn on_pressed_enter(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
-    ui_handle.unwrap().on_enter_callback(move || {
-        let ui = ui_handle.unwrap();
+    let weak = ui_handle.clone();
+    let (db_clone, state_clone) = (Rc::clone(&db), Rc::clone(&state));
+    ui_handle.unwrap().on_enter_callback(move |new_tab: bool| {
+        let ui = weak.unwrap();
+        let results = ui.invoke_get_search_results();
+        if let Some(result) = results.iter().find(|r| r.selected) {
+            open_result(&db_clone, &state_clone, &ui, result, new_tab);
+        }
+    });
 
-        let current_results = ui.invoke_get_search_results();
-        for x in 0..current_results.row_count() {
-            let result = current_results.row_data(x).unwrap();
-            if result.selected {
-                ui.invoke_hide_popups();
-                open_file(&mut db.borrow_mut(), &mut state.borrow_mut(), ui.as_weak(), Some(result.file_path.to_string()));
-                ui.invoke_highlight(result.start, result.end);
-                break;
-            }
+    let weak = ui_handle.clone();
+    ui_handle.unwrap().on_open_result(move |index: i32, new_tab: bool| {
+        let ui = weak.unwrap();
+        select_row(&ui, index as usize);
+        if let Some(result) = ui.invoke_get_search_results().row_data(index as usize) {
+            open_result(&db, &state, &ui, result, new_tab);
+        }
+    });
+}
+
+// Results open in the active tab so stepping through them doesn't pile up
+// tabs, unless a new tab is asked for
+fn open_result(db: &Rc<RefCell<Database>>, state: &Rc<RefCell<State>>, ui: &AppWindow, result: SearchResult, new_tab: bool) {
+    remember_search(&mut state.borrow_mut(), ui);
+    ui.invoke_hide_popups();
+    let open = if new_tab { open_tab } else { reuse_tab };
+    open(&mut db.borrow_mut(), &mut state.borrow_mut(), ui, result.file_path.as_str());
+    if result.line > 0 {
+        let line_count = ui.invoke_get_current_box().lines().count().max(1);
+        ui.invoke_scroll_to_line(result.line, line_count as i32);
+    }
+    // The offsets are bytes into the text that was searched
+    select_text(ui, result.start as usize, result.end as usize);
+}
+
+// Moves the selection by `delta` rows. Tab wraps around the ends, the arrow
+// and page keys stop at them. Returns the newly selected row.
+pub fn on_move_result(ui_handle: Weak<AppWindow>) {
+    let weak = ui_handle.clone();
+    ui_handle.unwrap().on_move_result(move |delta: i32, wrap: bool| {
+        let ui = weak.unwrap();
+        let results = ui.invoke_get_search_results();
+        let count = results.row_count() as i32;
+        if count == 0 {
+            return 0;
         }
+        let current = results.iter().position(|r| r.selected).map(|x| x as i32);
+        let target = match current {
+            None => if delta < 0 { count - 1 } else { 0 },
+            Some(x) if wrap => (x + delta).rem_euclid(count),
+            Some(x) => (x + delta).clamp(0, count - 1),
+        };
+        select_row(&ui, target as usize);
+        target
     });
+
+    ui_handle.unwrap().on_select_result(move |index: i32| select_row(&ui_handle.unwrap(), index as usize));
 }
 
-pub fn on_move_down(ui_handle: Weak<AppWindow>) {
-    ui_handle.unwrap().on_move_down_result(move || {
+fn select_row(ui: &AppWindow, index: usize) {
+    let results = ui.invoke_get_search_results();
+    for x in 0..results.row_count() {
+        let mut result = results.row_data(x).unwrap();
+        if result.selected != (x == index) {
+            result.selected = x == index;
+            results.set_row_data(x, result);
+        }
+    }
+}
+
+// Where the worker thread gets a file's text from. Files open in the editor
+// may have unsaved edits, so their text is handed over as is.
+enum Source {
+    Memory(String),
+    Disk(PathBuf)
+}
+
+// Everything a search needs, gathered on the UI thread so the worker never
+// touches the Database
+struct SearchJob {
+    id: usize,
+    // Every text file in key order, with its source when its contents need searching
+    files: Vec<(String, Option<Source>)>,
+    pattern: Regex,
+    match_name: bool,
+    replace: bool,
+    replacement: String,
+    regex: bool,
+    limit: usize
+}
+
+pub fn on_search(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    // Bumped on every keystroke. A running search that sees a newer value
+    // stops, and batches from it that are still queued get dropped.
+    let generation = Arc::new(AtomicUsize::new(0));
+
+    ui_handle.unwrap().on_search(move |options: SearchOptions, terms: SharedString, replace: bool, replacement: SharedString| {
+        let id = generation.fetch_add(1, Ordering::SeqCst) + 1;
         let ui = ui_handle.unwrap();
+        state.borrow_mut().search = SearchSettings::from(&options);
+        ui.invoke_set_search_results(Rc::new(VecModel::<SearchResult>::default()).into(), false);
+        ui.invoke_set_search_progress(0, 0, false);
+        if terms.is_empty() {
+            return;
+        }
+        let Some(pattern) = build_pattern(terms.as_str(), options.regex, options.match_case, options.whole_word) else { return };
+        let Some(filter) = PathFilter::new(&options.include, &options.exclude, &options.scope) else {
+            debug!("Invalid glob in {:?} or {:?}", options.include, options.exclude);
+            return;
+        };
 
-        let current_results = ui.invoke_get_search_results();
-        let mut found = false;
-        for mut x in 0..current_results.row_count() {
-            let mut result = current_results.row_data(x).unwrap();
-            if result.selected {
-                found = true;
-                result.selected = false;
-                current_results.set_row_data(x, result.clone());
-                if x + 1 >= current_results.row_count() {
-                    x = 0;
-                    result = current_results.row_data(0).unwrap();
-                } else {
-                    continue;
+        let binding = db.borrow();
+        // Files that can't contain the terms are never read
+        let candidates = binding.search_candidates(terms.as_str(), options.regex, options.match_case);
+        let mut files = binding.data.iter()
+            .filter(|(key, _)| filter.matches(key))
+            .filter_map(|(key, file)| match file {
+                FileType::Text(text_file) => {
+                    let candidate = (options.match_contents || replace) && candidates.as_ref().is_none_or(|c| c.contains(key));
+                    let source = candidate.then(|| match &text_file.content {
+                        Some(content) => Source::Memory(content.clone()),
+                        None => Source::Disk(text_file.path.clone()),
+                    });
+                    Some((key.clone(), source))
                 }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        drop(binding);
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let job = SearchJob {
+            id,
+            files,
+            pattern,
+            match_name: options.match_name,
+            replace,
+            replacement: replacement.to_string(),
+            regex: options.regex,
+            limit: state.borrow().search_result_limit
+        };
+        let (generation, weak) = (Arc::clone(&generation), ui.as_weak());
+        thread::spawn(move || run_search(job, generation, weak));
+    });
+}
+
+fn run_search(job: SearchJob, generation: Arc<AtomicUsize>, weak: Weak<AppWindow>) {
+    let cancelled = || generation.load(Ordering::SeqCst) != job.id;
+    let total = job.files.iter().filter(|(_, source)| source.is_some()).count();
+    let mut searched = 0;
+    let mut found = 0;
+    let mut batch = Vec::new();
+    let mut last_flush = Instant::now();
+    let mut truncated = false;
+
+    for (key, source) in &job.files {
+        if cancelled() {
+            return;
+        }
+        if found >= job.limit {
+            truncated = true;
+            break;
+        }
+        if let Some(source) = source {
+            let contents = match source {
+                Source::Memory(content) => Some(content.clone()),
+                // Files that aren't UTF-8 text can't be matched or replaced in
+                Source::Disk(path) => fs::read_to_string(path)
+                    .map_err(|e| debug!("Skipping {} in search: {}", key, e))
+                    .ok(),
+            };
+            if let Some(contents) = contents {
+                let rows = search_contents(&job, key, &contents, job.limit - found);
+                truncated |= rows.truncated;
+                found += rows.results.len();
+                batch.extend(rows.results);
             }
+            searched += 1;
+        }
+        // Replacing only touches file contents, so names aren't listed then
+        if job.match_name && !job.replace && found < job.limit && job.pattern.is_match(key) {
+            batch.push(name_result(key));
+            found += 1;
+        }
 
-            if found {
-                result.selected = true;
-                current_results.set_row_data(x, result.clone());
-                return x as i32;
+        if batch.len() >= BATCH_SIZE || last_flush.elapsed() >= BATCH_INTERVAL {
+            send_batch(&weak, &generation, job.id, std::mem::take(&mut batch), (searched, total), false, false);
+            last_flush = Instant::now();
+        }
+    }
+    send_batch(&weak, &generation, job.id, batch, (searched, total), truncated, true);
+    debug!("Matches found {}", found);
+}
+
+// Appends a batch to the results on the UI thread, unless a newer search has
+// started since
+fn send_batch(weak: &Weak<AppWindow>, generation: &Arc<AtomicUsize>, id: usize, mut batch: Vec<SearchResult>, (searched, total): (usize, usize), truncated: bool, finished: bool) {
+    let generation = Arc::clone(generation);
+    let _ = weak.upgrade_in_event_loop(move |ui| {
+        if generation.load(Ordering::SeqCst) != id {
+            return;
+        }
+        let results = ui.invoke_get_search_results();
+        if let Some(results) = results.as_any().downcast_ref::<VecModel<SearchResult>>() {
+            if results.row_count() == 0 {
+                if let Some(first) = batch.first_mut() {
+                    first.selected = true;
+                }
             }
+            results.extend(batch);
+        }
+        ui.invoke_set_search_progress(searched as i32, total as i32, !finished);
+        if truncated {
+            ui.invoke_set_search_results(results, true);
         }
-        return 0;
     });
 }
 
-pub fn on_search(db: Rc<RefCell<Database>>, ui_handle: Weak<AppWindow>) {
+struct FileResults {
+    results: Vec<SearchResult>,
+    truncated: bool
+}
 
-    ui_handle.unwrap().on_search(move |match_name: bool, match_contents: bool, match_case: bool, regex: bool, terms: SharedString| {
-        if terms.is_empty() {
-            return;
+// One header for the file, then a row for each match under it, at most `room` rows
+fn search_contents(job: &SearchJob, key: &str, contents: &str, room: usize) -> FileResults {
+    let matches = find_all(&job.pattern, contents);
+    let mut results = Vec::new();
+    let Some(&(first_start, first_end)) = matches.first() else {
+        return FileResults { results, truncated: false };
+    };
+    let starts = line_starts(contents);
+    let lines = contents.lines().collect::<Vec<_>>();
+
+    let (first_line, first_column) = locate(contents, &starts, first_start);
+    results.push(SearchResult {
+        file_path: SharedString::from(key),
+        line_matched: SharedString::from(""),
+        matched: SharedString::from(""),
+        after_match: SharedString::from(""),
+        match_name: false,
+        match_contents: true,
+        header: true,
+        count: matches.len() as i32,
+        line: first_line as i32 + 1,
+        column: first_column as i32,
+        before: SharedString::from(""),
+        after: SharedString::from(""),
+        preview: SharedString::from(""),
+        included: true,
+        selected: false,
+        start: first_start as i32,
+        end: first_end as i32
+    });
+    for &(start, end) in &matches {
+        if results.len() >= room {
+            return FileResults { results, truncated: true };
+        }
+        let (line, column) = locate(contents, &starts, start);
+        let context = |range: std::ops::Range<usize>| range
+            .filter_map(|n| lines.get(n).map(|text| format!("{}-{}", n + 1, clip(text, MAX_LINE_CHARS))))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (before, after) = around(contents, starts[line], start, end);
+        let (prefix, matched, suffix) = snippet(before, &contents[start..end], after);
+        let preview = if job.replace {
+            replacement_at(&job.pattern, contents, start, end, &job.replacement, job.regex)
+                .map(|text| {
+                    let (prefix, replaced, suffix) = snippet(before, &text, after);
+                    format!("{}:{}:{}{}{}", line + 1, column, prefix, replaced, suffix)
+                })
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        results.push(SearchResult {
+            file_path: SharedString::from(key),
+            line_matched: SharedString::from(format!("{}:{}:{}", line + 1, column, prefix)),
+            matched: SharedString::from(matched),
+            after_match: SharedString::from(suffix),
+            match_name: false,
+            match_contents: true,
+            header: false,
+            count: 0,
+            line: line as i32 + 1,
+            column: column as i32,
+            before: SharedString::from(context(line.saturating_sub(CONTEXT_LINES)..line)),
+            after: SharedString::from(context(line + 1..line + 1 + CONTEXT_LINES)),
+            preview: SharedString::from(preview),
+            included: true,
+            selected: false,
+            start: start as i32,
+            end: end as i32
+        });
+    }
+    FileResults { results, truncated: false }
+}
+
+fn name_result(key: &str) -> SearchResult {
+    SearchResult {
+        file_path: SharedString::from(key),
+        line_matched: SharedString::from(""),
+        matched: SharedString::from(""),
+        after_match: SharedString::from(""),
+        match_name: true,
+        match_contents: false,
+        header: false,
+        count: 0,
+        line: 0,
+        column: 0,
+        before: SharedString::from(""),
+        after: SharedString::from(""),
+        preview: SharedString::from(""),
+        included: false,
+        selected: false,
+        start: 0,
+        end: 0
+    }
+}
+
+// Limits the search to the selected folder, or the folder of the selected file
+pub fn on_search_in_folder(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_search_in_folder(move || {
+        let ui = ui_handle.unwrap();
+        let binding = db.borrow();
+        let Some(key) = binding.selection.first().cloned() else { return };
+        let folder = if binding.data.contains_key(&key) {
+            key.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default()
+        } else {
+            key
+        };
+        let mut state = state.borrow_mut();
+        state.search.scope = folder;
+        ui.set_search_options(SearchOptions::from(&state.search));
+        ui.invoke_show_search_popup();
+    });
+}
+
+// Searches only go into the history once they were used to open or replace
+// something, so half typed terms don't crowd it
+fn remember_search(state: &mut State, ui: &AppWindow) {
+    state.push_search(ui.get_search_terms().as_str());
+    ui.set_search_history(queries(&state.search_history));
+}
+
+fn queries(searches: &[SavedSearch]) -> ModelRc<SearchQuery> {
+    let queries = searches.iter().map(|s| SearchQuery {
+        name: SharedString::from(s.name.as_str()),
+        terms: SharedString::from(s.terms.as_str()),
+        options: SearchOptions::from(&s.settings)
+    }).collect::<Vec<_>>();
+    Rc::new(VecModel::from(queries)).into()
+}
+
+pub fn on_search_history(state: Rc<RefCell<State>>, weak: Weak<AppWindow>) {
+    let ui = weak.unwrap();
+    ui.set_search_history(queries(&state.borrow().search_history));
+    ui.set_saved_searches(queries(&state.borrow().saved_searches));
+
+    let (ui_handle, state_clone) = (weak.clone(), Rc::clone(&state));
+    ui.on_save_search(move |name: SharedString| {
+        let ui = ui_handle.unwrap();
+        let mut state = state_clone.borrow_mut();
+        // Saving under an existing name updates that search
+        let saved = SavedSearch { name: name.to_string(), terms: ui.get_search_terms().to_string(), settings: state.search.clone() };
+        match state.saved_searches.iter().position(|s| s.name == saved.name) {
+            Some(index) => state.saved_searches[index] = saved,
+            None => state.saved_searches.push(saved),
         }
+        ui.set_saved_searches(queries(&state.saved_searches));
+    });
 
+    let (ui_handle, state_clone) = (weak.clone(), Rc::clone(&state));
+    ui.on_run_saved_search(move |index: i32| {
         let ui = ui_handle.unwrap();
-        let mut binding = db.borrow_mut();
-        let mut terms = terms.to_string();
-        let mut results: Vec<SearchResult> = Vec::new();
-        let mut selected_first = false;
+        let mut state = state_clone.borrow_mut();
+        let Some(saved) = state.saved_searches.get(index as usize).cloned() else { return };
+        state.search = saved.settings;
+        ui.set_search_options(SearchOptions::from(&state.search));
+        ui.set_search_terms(SharedString::from(saved.terms));
+        ui.invoke_hide_popups();
+        ui.invoke_show_search_popup();
+    });
 
-        if !match_case {
-            terms = terms.to_lowercase().to_string();
+    let ui_handle = weak.clone();
+    ui.on_remove_saved_search(move |index: i32| {
+        let mut state = state.borrow_mut();
+        if (index as usize) < state.saved_searches.len() {
+            state.saved_searches.remove(index as usize);
         }
+        ui_handle.unwrap().set_saved_searches(queries(&state.saved_searches));
+    });
+}
 
-        for (key, file_type) in &mut binding.data {
-            if let FileType::Text(ref mut text_file) = file_type {
-                if match_contents {
-                    let mut contents = text_file.get_contents().clone();
+impl From<&SearchSettings> for SearchOptions {
+    fn from(settings: &SearchSettings) -> Self {
+        SearchOptions {
+            match_name: settings.match_name,
+            match_contents: settings.match_contents,
+            match_case: settings.match_case,
+            regex: settings.regex,
+            whole_word: settings.whole_word,
+            include: SharedString::from(settings.include.as_str()),
+            exclude: SharedString::from(settings.exclude.as_str()),
+            scope: SharedString::from(settings.scope.as_str())
+        }
+    }
+}
 
-                    if !match_case {
-                        contents = contents.to_lowercase();
-                    }
-                    let opt = find(regex, terms.to_string(), contents.to_string());
-
-                    if opt.is_some() {
-                        let start = opt.unwrap();
-                        let snippet_start = max((start as i32) - 8, 0) as usize;
-                        let snippet_end = min(start + terms.len() + 8, contents.len());
-                        let snippet = &mut contents[snippet_start..snippet_end].replace("\n", "\\n");
-
-                        if snippet_start > 0 {
-                            snippet.insert_str(0, "...");
-                        }
-                        if snippet_end < contents.len() {
-                            snippet.push_str("...");
-                        }
-                        results.push(SearchResult {
-                            file_path: SharedString::from(key.clone()),
-                            line_matched: SharedString::from(snippet.clone()),
-                            match_name: false,
-                            match_contents: true,
-                            selected: !selected_first,
-                            start: start as i32,
-                            end: start as i32 + terms.len() as i32
-                        });
-                        selected_first = true;
-                    }
-                }
+impl From<&SearchOptions> for SearchSettings {
+    fn from(options: &SearchOptions) -> Self {
+        SearchSettings {
+            match_name: options.match_name,
+            match_contents: options.match_contents,
+            match_case: options.match_case,
+            regex: options.regex,
+            whole_word: options.whole_word,
+            include: options.include.to_string(),
+            exclude: options.exclude.to_string(),
+            scope: options.scope.to_string()
+        }
+    }
+}
 
-                if match_name {
-                    let opt = find(regex, terms.to_string(), key.to_string());
-                    if opt.is_some() {
-                        results.push(SearchResult {
-                            file_path: SharedString::from(key.clone()),
-                            line_matched: SharedString::from(""),
-                            match_name: true,
-                            match_contents: false,
-                            selected: !selected_first,
-                            start: 0,
-                            end: 0
-                        });
-
-                        selected_first = true;
+// Including or excluding a file's header row does the same for all its matches
+pub fn on_toggle_result(ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_toggle_result(move |index: i32| {
+        let results = ui_handle.unwrap().invoke_get_search_results();
+        let Some(row) = results.row_data(index as usize) else { return };
+        let included = !row.included;
+        for x in 0..results.row_count() {
+            let mut result = results.row_data(x).unwrap();
+            let in_file = row.header && result.match_contents && result.file_path == row.file_path;
+            if x == index as usize || in_file {
+                result.included = included;
+                results.set_row_data(x, result);
+            }
+        }
+    });
+}
+
+// Replaces every included match, checking each one still matches the text it
+// was found in. All files change in one operation, so a single undo in the
+// file tree puts everything back.
+pub fn on_apply_replace(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, model: Rc<FileTreeModel>, notifier: Rc<Notifier>, ui_handle: Weak<AppWindow>) {
+    ui_handle.unwrap().on_apply_replace(move |options: SearchOptions, terms: SharedString, replacement: SharedString| {
+        let ui = ui_handle.unwrap();
+        let regex = options.regex;
+        let Some(pattern) = build_pattern(terms.as_str(), regex, options.match_case, options.whole_word) else { return };
+        remember_search(&mut state.borrow_mut(), &ui);
+
+        let mut by_file: BTreeMap<String, Vec<(usize, usize)>> = BTreeMap::new();
+        for result in ui.invoke_get_search_results().iter() {
+            if result.included && result.match_contents && !result.header {
+                by_file.entry(result.file_path.to_string()).or_default().push((result.start as usize, result.end as usize));
+            }
+        }
+
+        let mut count = 0;
+        let mut stale = 0;
+        let replaced = run_file_operation(&db, &state, &model, &notifier, &ui, |db| {
+            let mut changes = Vec::new();
+            for (key, ranges) in &by_file {
+                let Some(FileType::Text(text_file)) = db.data.get_mut(key) else { continue };
+                let contents = text_file.get_contents();
+                let mut edits = Vec::new();
+                for &(start, end) in ranges {
+                    match replacement_at(&pattern, &contents, start, end, replacement.as_str(), regex) {
+                        Some(text) => edits.push((start, end, text)),
+                        None => stale += 1,
                     }
                 }
+                if !edits.is_empty() {
+                    count += edits.len();
+                    changes.push((key.clone(), apply_edits(&contents, &edits)));
+                }
             }
+            db.replace_contents(changes).map(|_| Vec::new())
+        });
+
+        ui.invoke_set_search_results(Rc::new(slint::VecModel::from(Vec::<SearchResult>::new())).into(), false);
+        ui.invoke_hide_popups();
+        if replaced {
+            notifier.info(format!("Replaced {} match{} in {} file{}", count, if count == 1 { "" } else { "es" }, by_file.len(), if by_file.len() == 1 { "" } else { "s" }));
+        }
+        if stale > 0 {
+            notifier.error(format!("Skipped {} match{}", stale, if stale == 1 { "" } else { "es" }), "The text changed since searching, search again to replace those");
         }
-        let rc = Rc::new(slint::VecModel::from(vec![]));
-        rc.set_vec(results.clone());
-        ui.invoke_set_search_results(rc.into());
-        println!("Matches found {}", results.clone().len())
     });
 }
 
+// The text on the match's lines before and after it
+fn around(contents: &str, line_start: usize, start: usize, end: usize) -> (&str, &str) {
+    let line_end = contents[end..].find('\n').map(|i| end + i).unwrap_or(contents.len());
+    (&contents[line_start..start], contents[end..line_end].trim_end_matches('\r'))
+}
 
-fn find(regex: bool, terms: String, str: String) -> Option<usize> {
-    if !regex {
-        return str.find(terms.as_str());
+// Cuts a line down to fit a result row, keeping the match in view and split
+// out so it can be highlighted. Long lines lose text from the start, which
+// happens on whole characters so any script survives the cut.
+fn snippet(before: &str, matched: &str, after: &str) -> (String, String, String) {
+    let skip = before.chars().count().saturating_sub(LEADING_CHARS);
+    let before = match before.char_indices().nth(skip) {
+        Some((i, _)) if skip > 0 => format!("...{}", &before[i..]),
+        _ => before.to_string(),
+    };
+    // A regex match can run over several lines
+    let matched = clip(&matched.replace(['\r', '\n'], " "), MAX_LINE_CHARS);
+    let room = MAX_LINE_CHARS.saturating_sub(before.chars().count() + matched.chars().count());
+    let after = clip(after, room);
+    (before, matched, after)
+}
+
+// Keeps minified files and other huge lines from blowing up the result list
+fn clip(line: &str, max_chars: usize) -> String {
+    match line.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
     }
-    let reg = Regex::new(terms.as_str());
-    return if !reg.is_ok() {
-        None
-    } else {
-        let find = reg.unwrap().find(str.as_str());
-        if find.is_some() {
-            Some(find.unwrap().start())
-        } else {
-            None
+}
+
+// Keeps the search index in step with changes made outside the editor, for
+// as long as the editor runs. Files are stat'ed and read on a worker thread,
+// only the trigrams that came out of it are folded in on the UI thread. The
+// index is written out when the folder is closed, not after every refresh.
+// Every look stats each file in the workspace, so while nothing changes the
+// looks get further apart. Saves from the editor reach the index right away.
+pub fn refresh_index(db: Rc<RefCell<Database>>) {
+    scan_index(db, INDEX_REFRESH_INTERVAL);
+}
+
+fn scan_index(db: Rc<RefCell<Database>>, interval: Duration) {
+    let scan = db.borrow().index_scan();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(scan.run());
+    });
+    wait_for_scan(db, receiver, interval);
+}
+
+fn wait_for_scan(db: Rc<RefCell<Database>>, receiver: mpsc::Receiver<Vec<Scanned>>, interval: Duration) {
+    Timer::single_shot(INDEX_POLL_INTERVAL, move || match receiver.try_recv() {
+        Err(mpsc::TryRecvError::Empty) => wait_for_scan(db, receiver, interval),
+        result => {
+            let changed = result.map_or(0, |scanned| db.borrow_mut().apply_index_scan(scanned));
+            let next = if changed > 0 { INDEX_REFRESH_INTERVAL } else { (interval * 2).min(INDEX_REFRESH_LONGEST) };
+            Timer::single_shot(next, move || scan_index(db, next));
         }
-    }
-}
\ No newline at end of file
+    });
+}
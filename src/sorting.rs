@@ -0,0 +1,157 @@
+use std::cmp::Ordering;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::file_types::FileInfo;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum SortMode {
+    #[default]
+    Name,
+    Modified,
+    Created,
+    Size
+}
+
+impl SortMode {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "Modified" => SortMode::Modified,
+            "Created" => SortMode::Created,
+            "Size" => SortMode::Size,
+            _ => SortMode::Name,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortMode::Name => "Name",
+            SortMode::Modified => "Modified",
+            SortMode::Created => "Created",
+            SortMode::Size => "Size",
+        }
+    }
+}
+
+// Orders two siblings in the tree. Each side is (name, is folder, info),
+// where a folder's info is the total of everything inside it.
+pub fn compare_entries(a: (&str, bool, &FileInfo), b: (&str, bool, &FileInfo), mode: SortMode, folders_first: bool) -> Ordering {
+    let (a_name, a_folder, a_info) = a;
+    let (b_name, b_folder, b_info) = b;
+    if folders_first && a_folder != b_folder {
+        return if a_folder { Ordering::Less } else { Ordering::Greater };
+    }
+
+    let by_mode = match mode {
+        SortMode::Name => Ordering::Equal,
+        // Newest and largest first, that's what you're usually looking for
+        SortMode::Modified => b_info.modified.cmp(&a_info.modified),
+        SortMode::Created => b_info.created.cmp(&a_info.created),
+        SortMode::Size => b_info.size.cmp(&a_info.size),
+    };
+    by_mode.then_with(|| natural_cmp(a_name, b_name))
+}
+
+pub fn latest(a: Option<SystemTime>, b: Option<SystemTime>) -> Option<SystemTime> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// Compares names the way people read them: "note 2" comes before "note 10",
+// and case only matters when everything else is equal.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_num = take_number(&mut a_chars);
+                let b_num = take_number(&mut b_chars);
+                let a_trimmed = a_num.trim_start_matches('0');
+                let b_trimmed = b_num.trim_start_matches('0');
+                let ordering = a_trimmed.len().cmp(&b_trimmed.len())
+                    .then_with(|| a_trimmed.cmp(b_trimmed))
+                    .then_with(|| a_num.len().cmp(&b_num.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().copied() {
+        if !c.is_ascii_digit() { break; }
+        number.push(c);
+        chars.next();
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::time::{Duration, SystemTime};
+    use super::*;
+
+    #[test]
+    fn numbers_compare_by_value() {
+        assert_eq!(natural_cmp("note 2", "note 10"), Ordering::Less);
+        assert_eq!(natural_cmp("note 10", "note 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("v1.10.2", "v1.9.12"), Ordering::Greater);
+        assert_eq!(natural_cmp("12345678901234567890a", "9a"), Ordering::Greater);
+    }
+
+    #[test]
+    fn leading_zeros_only_break_ties() {
+        assert_eq!(natural_cmp("file007", "file7"), Ordering::Greater);
+        assert_eq!(natural_cmp("file007", "file8"), Ordering::Less);
+    }
+
+    #[test]
+    fn case_only_breaks_ties() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("Readme", "readme"), Ordering::Less);
+        assert_eq!(natural_cmp("readme", "readme"), Ordering::Equal);
+    }
+
+    #[test]
+    fn prefixes_come_first() {
+        assert_eq!(natural_cmp("notes", "notes 2"), Ordering::Less);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+    }
+
+    #[test]
+    fn sorts_a_list() {
+        let mut names = vec!["b10", "a2", "B2", "a10", "a1", "b1"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["a1", "a2", "a10", "b1", "B2", "b10"]);
+    }
+
+    #[test]
+    fn folders_first_then_mode_then_name() {
+        let old = FileInfo { size: 10, modified: Some(SystemTime::UNIX_EPOCH), created: None };
+        let new = FileInfo { size: 5, modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(60)), created: None };
+        assert_eq!(compare_entries(("b", true, &old), ("a", false, &new), SortMode::Name, true), Ordering::Less);
+        assert_eq!(compare_entries(("b", true, &old), ("a", false, &new), SortMode::Name, false), Ordering::Greater);
+        assert_eq!(compare_entries(("a", false, &old), ("b", false, &new), SortMode::Modified, true), Ordering::Greater);
+        assert_eq!(compare_entries(("a", false, &old), ("b", false, &new), SortMode::Size, true), Ordering::Less);
+        assert_eq!(compare_entries(("a2", false, &old), ("a10", false, &old), SortMode::Size, true), Ordering::Less);
+    }
+}
@@ -1,14 +1,118 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
+use crate::panes::Split;
+use crate::sorting::SortMode;
+
+const STATE_FILE: &str = "state.json";
+const BACKUP_FILE: &str = "state.json.bak";
+const RECENT_FILES: usize = 50;
+const SEARCH_HISTORY: usize = 30;
 
 #[derive(Serialize, Deserialize)]
 pub struct State {
     pub data_dir: String,
     pub background_image_path: Option<String>,
     pub last_open_file: Option<String>,
-    pub theme: String
+    pub theme: String,
+    #[serde(default = "default_true")]
+    pub follow_symlinks: bool,
+    #[serde(default)]
+    pub sort_mode: SortMode,
+    #[serde(default = "default_true")]
+    pub folders_first: bool,
+    #[serde(default = "default_result_limit")]
+    pub search_result_limit: usize,
+    // Write the undo history to disk so it outlives the session
+    #[serde(default)]
+    pub keep_history: bool,
+    // Line numbers counted from the cursor's line
+    #[serde(default)]
+    pub relative_numbers: bool,
+    // Most recently opened first
+    #[serde(default)]
+    pub recent_files: Vec<String>,
+    #[serde(default)]
+    pub search: SearchSettings,
+    // Most recent first, recalled with the arrow keys in the search box
+    #[serde(default)]
+    pub search_history: Vec<SavedSearch>,
+    // Named by the user and listed in the sidebar
+    #[serde(default)]
+    pub saved_searches: Vec<SavedSearch>,
+    // Open files in the order of the tab strip
+    #[serde(default)]
+    pub tabs: Vec<OpenTab>,
+    #[serde(default)]
+    pub active_tab: Option<usize>,
+    #[serde(default)]
+    pub split: Split,
+    // File shown in the second pane while the editor is split
+    #[serde(default)]
+    pub side: Option<OpenTab>
+}
+
+// What a tab remembers while another one is shown. The text itself lives in
+// the Database, like any other unsaved change.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OpenTab {
+    pub key: String,
+    #[serde(default)]
+    pub scroll: f32,
+    // The match last highlighted for this tab, e.g. from a search, until the
+    // text is edited. The editor doesn't report what the user selects, so
+    // that isn't kept.
+    #[serde(default)]
+    pub highlight: Option<(i32, i32)>
+}
+
+// The search window's options, as they were left when it was last used
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SearchSettings {
+    pub match_name: bool,
+    pub match_contents: bool,
+    pub match_case: bool,
+    pub regex: bool,
+    pub whole_word: bool,
+    pub include: String,
+    pub exclude: String,
+    // Folder the search is limited to, empty for everywhere
+    pub scope: String
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedSearch {
+    #[serde(default)]
+    pub name: String,
+    pub terms: String,
+    #[serde(default)]
+    pub settings: SearchSettings
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        SearchSettings {
+            match_name: true,
+            match_contents: true,
+            match_case: false,
+            regex: false,
+            whole_word: false,
+            include: String::new(),
+            exclude: String::new(),
+            scope: String::new()
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_result_limit() -> usize {
+    1000
 }
 
 impl State {
@@ -17,32 +121,134 @@ impl State {
             data_dir: "cache".to_string(),
             background_image_path: None,
             last_open_file: None,
-            theme: "Default".to_string()
+            theme: "Default".to_string(),
+            follow_symlinks: true,
+            sort_mode: SortMode::Name,
+            folders_first: true,
+            search_result_limit: default_result_limit(),
+            keep_history: false,
+            relative_numbers: false,
+            recent_files: Vec::new(),
+            search: SearchSettings::default(),
+            search_history: Vec::new(),
+            saved_searches: Vec::new(),
+            tabs: Vec::new(),
+            active_tab: None,
+            split: Split::None,
+            side: None
         }
     }
 
-    pub fn read(self) -> Result<Self, ()> {
+    pub fn push_recent(&mut self, key: &str) {
+        self.recent_files.retain(|k| k != key);
+        self.recent_files.insert(0, key.to_string());
+        self.recent_files.truncate(RECENT_FILES);
+    }
+
+    pub fn current_file(&self) -> Option<&str> {
+        self.active_tab.and_then(|i| self.tabs.get(i)).map(|tab| tab.key.as_str())
+    }
+
+    // Makes the file's tab active, opening a new one next to the active tab
+    // if it isn't open yet
+    pub fn open_tab(&mut self, key: &str) {
+        let index = match self.tabs.iter().position(|tab| tab.key == key) {
+            Some(index) => index,
+            None => {
+                let at = self.active_tab.map_or(self.tabs.len(), |i| (i + 1).min(self.tabs.len()));
+                self.tabs.insert(at, OpenTab { key: key.to_string(), scroll: 0.0, highlight: None });
+                at
+            }
+        };
+        self.active_tab = Some(index);
+    }
+
+    // Like open_tab, but a file that isn't open yet takes the active tab's
+    // place instead of getting one of its own
+    pub fn reuse_tab(&mut self, key: &str) {
+        let open = self.tabs.iter().any(|tab| tab.key == key);
+        match self.active_tab.and_then(|i| self.tabs.get_mut(i)) {
+            Some(tab) if !open => {
+                *tab = OpenTab { key: key.to_string(), scroll: 0.0, highlight: None };
+            }
+            _ => self.open_tab(key),
+        }
+    }
+
+    // Remembers the terms with the options they were searched with
+    pub fn push_search(&mut self, terms: &str) {
+        if terms.is_empty() {
+            return;
+        }
+        self.search_history.retain(|s| s.terms != terms);
+        self.search_history.insert(0, SavedSearch { name: String::new(), terms: terms.to_string(), settings: self.search.clone() });
+        self.search_history.truncate(SEARCH_HISTORY);
+    }
+
+    // A missing file means a first run and keeps the defaults. A file that
+    // can't be parsed is moved aside so saving the defaults doesn't wipe it.
+    pub fn read(self) -> io::Result<Self> {
         let mut content = String::new();
-        let file = File::open("state.json");
+        let file = File::open(STATE_FILE);
         if !file.is_ok() {
             return Ok(self);
         }
-        file.unwrap().read_to_string(&mut content).expect("Failed to load state");
+        file?.read_to_string(&mut content)?;
 
-        let state: State = serde_json::from_str(&content).unwrap();
-        return Ok(state);
+        match serde_json::from_str(&content) {
+            Ok(state) => Ok(state),
+            Err(e) => {
+                rename(STATE_FILE, BACKUP_FILE)?;
+                Err(io::Error::new(ErrorKind::InvalidData, format!("{}, the old file was kept as {}", e, BACKUP_FILE)))
+            }
+        }
     }
 
-    pub fn save(&self) -> Result<(), ()> {
-        let json = to_string_pretty(self);
+    pub fn save(&self) -> io::Result<()> {
+        let json = to_string_pretty(self)?;
         let mut file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
-            .open("state.json").expect("aaaaa");
+            .open(STATE_FILE)?;
 
-        writeln!(&mut file, "{}", json.unwrap()).expect("caralho");
-        println!("Saved state");
+        writeln!(&mut file, "{}", json)?;
+        info!("Saved state");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(state: &State) -> Vec<&str> {
+        state.tabs.iter().map(|tab| tab.key.as_str()).collect()
+    }
+
+    #[test]
+    fn reusing_a_tab_replaces_the_active_file() {
+        let mut state = State::new();
+        state.reuse_tab("a.txt");
+        state.reuse_tab("b.txt");
+        assert_eq!(keys(&state), ["b.txt"]);
+        assert_eq!(state.current_file(), Some("b.txt"));
+    }
+
+    #[test]
+    fn opening_a_new_tab_keeps_the_active_one() {
+        let mut state = State::new();
+        state.open_tab("a.txt");
+        state.open_tab("c.txt");
+        state.active_tab = Some(0);
+        state.open_tab("b.txt");
+        assert_eq!(keys(&state), ["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(state.current_file(), Some("b.txt"));
+
+        // Files that are already open are switched to either way
+        state.open_tab("a.txt");
+        state.reuse_tab("c.txt");
+        assert_eq!(keys(&state), ["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(state.current_file(), Some("c.txt"));
+    }
+}
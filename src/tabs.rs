@@ -0,0 +1,199 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use slint::{Model, SharedString, VecModel, Weak};
+use crate::{AppWindow, TabData};
+use crate::cache::Database;
+use crate::file_tree::FileTreeModel;
+use crate::file_types::FileType;
+use crate::highlight::Pane;
+use crate::lines::show_rows;
+use crate::panes::{flush_side, show_side};
+use crate::state::State;
+
+// Writes the editors' text back into the Database and remembers where the
+// active tab was scrolled to, so another tab can take over the editor
+pub fn flush_buffer(db: &mut Database, state: &mut State, ui: &AppWindow) {
+    if let Some(tab) = state.active_tab.and_then(|i| state.tabs.get_mut(i)) {
+        tab.scroll = ui.invoke_get_editor_scroll();
+        tab.highlight = (ui.get_highlight_start() >= 0).then(|| (ui.get_highlight_start(), ui.get_highlight_end()));
+        write_back(db, &tab.key, ui.invoke_get_current_box().as_str());
+    }
+    flush_side(db, state, ui);
+}
+
+// Only text that actually changed is written back, otherwise every tab
+// switched through would end up marked dirty
+pub fn write_back(db: &mut Database, key: &str, text: &str) {
+    let changed = match db.data.get(key) {
+        Some(FileType::Text(text_file)) => !text_file.read_only && text_file.content.as_deref() != Some(text),
+        _ => false,
+    };
+    if changed {
+        db.insert(key.to_string(), text.to_string());
+    }
+}
+
+// Puts the active tab's file in the editor where it was left, or empties the
+// editor when no tab is open
+pub fn show_active(db: &mut Database, state: &mut State, ui: &AppWindow) {
+    let tab = state.active_tab.and_then(|i| state.tabs.get(i)).cloned();
+    if let Some(tree) = ui.get_files().as_any().downcast_ref::<FileTreeModel>() {
+        tree.set_open(tab.as_ref().map(|t| t.key.as_str()));
+    }
+    match tab {
+        Some(tab) => {
+            if let Some(FileType::Text(ref mut text_file)) = db.get_file(&tab.key) {
+                let contents = text_file.get_contents();
+                ui.invoke_set_open_file(SharedString::from(tab.key.as_str()), SharedString::from(contents.as_str()), text_file.read_only);
+                db.history(&tab.key).sync(&contents);
+                ui.set_editor_highlighted(db.highlights.open(Pane::Main, &tab.key, &contents));
+                db.layouts.open(Pane::Main, &contents);
+                show_rows(db, ui, Pane::Main);
+                if let Some((start, end)) = tab.highlight {
+                    select_text(ui, start as usize, end as usize);
+                }
+                ui.invoke_set_editor_scroll(tab.scroll);
+                state.last_open_file = Some(tab.key.clone());
+                state.push_recent(&tab.key);
+            }
+        }
+        None => {
+            ui.invoke_set_open_file(SharedString::new(), SharedString::new(), false);
+            db.highlights.close(Pane::Main);
+            db.layouts.close(Pane::Main);
+            ui.set_editor_highlighted(false);
+            show_rows(db, ui, Pane::Main);
+        }
+    }
+    show_tabs(db, state, ui);
+}
+
+pub fn show_tabs(db: &Database, state: &State, ui: &AppWindow) {
+    let tabs = state.tabs.iter().enumerate().map(|(i, tab)| TabData {
+        key: SharedString::from(tab.key.as_str()),
+        name: SharedString::from(tab.key.rsplit('/').next().unwrap_or(&tab.key)),
+        dirty: db.data.get(&tab.key).is_some_and(|file| file.is_dirty()),
+        active: Some(i) == state.active_tab
+    }).collect::<Vec<_>>();
+    ui.set_tabs(Rc::new(VecModel::from(tabs)).into());
+}
+
+// Shows the file in its tab, opening a new one next to the active tab if it
+// isn't open yet
+pub fn open_tab(db: &mut Database, state: &mut State, ui: &AppWindow, key: &str) {
+    flush_buffer(db, state, ui);
+    if !matches!(db.data.get(key), Some(FileType::Text(_))) {
+        return;
+    }
+    state.open_tab(key);
+    show_active(db, state, ui);
+}
+
+// Shows the file in the active tab, in place of the file there unless it's
+// already open in a tab of its own
+pub fn reuse_tab(db: &mut Database, state: &mut State, ui: &AppWindow, key: &str) {
+    flush_buffer(db, state, ui);
+    if !matches!(db.data.get(key), Some(FileType::Text(_))) {
+        return;
+    }
+    state.reuse_tab(key);
+    show_active(db, state, ui);
+}
+
+// Follows renamed files in the tabs and the side pane and closes tabs whose
+// file is gone, e.g. after a file operation or rescanning the folder. Also
+// used to restore the tabs on start.
+pub fn update_tabs(db: &mut Database, state: &mut State, ui: &AppWindow, renames: &[(String, String)]) {
+    let renamed = |key: &str| renames.iter().find(|(from, _)| from == key).map(|(_, to)| to.clone()).unwrap_or(key.to_string());
+    let active = state.current_file().map(renamed);
+    let previous = state.active_tab.unwrap_or(0);
+
+    let mut seen = Vec::new();
+    for tab in state.tabs.iter_mut().chain(state.side.as_mut()) {
+        tab.key = renamed(&tab.key);
+    }
+    state.tabs.retain(|tab| {
+        let keep = matches!(db.data.get(&tab.key), Some(FileType::Text(_))) && !seen.contains(&tab.key);
+        seen.push(tab.key.clone());
+        keep
+    });
+    state.active_tab = match active.and_then(|key| state.tabs.iter().position(|tab| tab.key == key)) {
+        Some(index) => Some(index),
+        None if state.tabs.is_empty() => None,
+        None => Some(previous.min(state.tabs.len() - 1)),
+    };
+    show_active(db, state, ui);
+    show_side(db, state, ui);
+}
+
+// Selects start..end in the editor if those are still character boundaries
+// in its text, which they may not be once the file changed
+pub fn select_text(ui: &AppWindow, start: usize, end: usize) {
+    let text = ui.invoke_get_current_box();
+    if start <= end && end <= text.len() && text.is_char_boundary(start) && text.is_char_boundary(end) {
+        ui.invoke_highlight(start as i32, end as i32);
+    }
+}
+
+pub fn handle_tabs(db: Rc<RefCell<Database>>, state: Rc<RefCell<State>>, weak: Weak<AppWindow>) {
+    let ui = weak.unwrap();
+
+    let (ui_handle, db_clone, state_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui.on_select_tab(move |index: i32| {
+        let ui = ui_handle.unwrap();
+        let (mut db, mut state) = (db_clone.borrow_mut(), state_clone.borrow_mut());
+        flush_buffer(&mut db, &mut state, &ui);
+        if (index as usize) < state.tabs.len() {
+            state.active_tab = Some(index as usize);
+        }
+        show_active(&mut db, &mut state, &ui);
+    });
+
+    // Closing only drops the tab, unsaved text stays in the Database and is
+    // saved with everything else
+    let (ui_handle, db_clone, state_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui.on_close_tab(move |index: i32| {
+        let ui = ui_handle.unwrap();
+        let (mut db, mut state) = (db_clone.borrow_mut(), state_clone.borrow_mut());
+        let index = index as usize;
+        if index >= state.tabs.len() {
+            return;
+        }
+        flush_buffer(&mut db, &mut state, &ui);
+        state.tabs.remove(index);
+        state.active_tab = match state.active_tab {
+            _ if state.tabs.is_empty() => None,
+            Some(active) if active > index => Some(active - 1),
+            Some(active) => Some(active.min(state.tabs.len() - 1)),
+            None => None,
+        };
+        show_active(&mut db, &mut state, &ui);
+    });
+
+    let (ui_handle, db_clone, state_clone) = (weak.clone(), Rc::clone(&db), Rc::clone(&state));
+    ui.on_close_other_tabs(move |index: i32| {
+        let ui = ui_handle.unwrap();
+        let (mut db, mut state) = (db_clone.borrow_mut(), state_clone.borrow_mut());
+        let Some(keep) = state.tabs.get(index as usize).cloned() else { return };
+        flush_buffer(&mut db, &mut state, &ui);
+        // The kept tab's scroll may have just been updated by the flush
+        let keep = state.tabs.iter().find(|tab| tab.key == keep.key).cloned().unwrap_or(keep);
+        state.tabs = vec![keep];
+        state.active_tab = Some(0);
+        show_active(&mut db, &mut state, &ui);
+    });
+
+    let ui_handle = weak.clone();
+    ui.on_move_tab(move |from: i32, to: i32| {
+        let (db, mut state) = (db.borrow(), state.borrow_mut());
+        let (from, to) = (from as usize, to as usize);
+        if from >= state.tabs.len() || to >= state.tabs.len() || from == to {
+            return;
+        }
+        let active = state.current_file().map(str::to_string);
+        let tab = state.tabs.remove(from);
+        state.tabs.insert(to, tab);
+        state.active_tab = active.and_then(|key| state.tabs.iter().position(|tab| tab.key == key));
+        show_tabs(&db, &state, &ui_handle.unwrap());
+    });
+}